@@ -1,168 +1,987 @@
+extern crate aws_tools;
 extern crate docopt;
-#[macro_use]
-extern crate lazy_static;
-extern crate regex;
-extern crate rusoto_core;
 extern crate rusoto_s3;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
-use regex::Regex;
-use rusoto_core::Region;
-use rusoto_s3::{S3, S3Client};
-use serde::de;
-use serde::de::{Deserialize, Deserializer};
-use std::fmt;
-use std::str::FromStr;
+use aws_tools::BoxError;
+use aws_tools::client::{self, ClientConfig};
+use aws_tools::filter::{self, ObjectFilter};
+use aws_tools::journal::Journal;
+use aws_tools::listing::{self, Listed};
+use aws_tools::manifest::{self, Entry};
+use aws_tools::output::{self, Action, Output, Record, Status};
+use aws_tools::pool::WorkerPool;
+use aws_tools::retry::RetryPolicy;
+use aws_tools::rewrite::KeyRewriter;
+use aws_tools::s3url::Location;
+use aws_tools::transfer::{Bucket, Completed, Mover, MoveOp, OnConflict, Store, TaggingDirective, WriteOptions};
+use rusoto_s3::S3Client;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 const USAGE: &'static str = "
-Move S3 objects from src to dest.
+Move S3 objects from src to dest, or copy them with --no-delete.
+
+Either <src-url> or <dest-url> may be a local directory, given as a path or file:// URL, to
+upload files to S3 or download objects from it. Keys map to paths relative to the directory.
+
+Objects copied within S3 keep their content headers, like Content-Type and Cache-Control, along
+with their user metadata and tags, unless told to replace them. This needs s3:GetObjectTagging on
+src objects that are copied in parts or streamed, or whose metadata or tags are replaced.
+
+Before a src object is deleted, its copy is checked: the sizes must match, and so must the ETags,
+unless the src or the copy is encrypted with SSE-KMS or SSE-C.
+Downloads of multipart src objects are checked against an ETag worked out from the parts they were
+uploaded in. Copies within S3 over 5 GiB, or any with --preserve-parts, are made in those same
+parts, so they get the same ETag. Other copies of multipart src objects, and those whose parts
+the src store doesn't report, only have their size checked.
+
+Each object is reported once it's been moved. With --output=text, that's a line of src key, dest
+key and size, and failures and skipped objects go to stderr. The other formats give a record for
+every object with src_key, version_id, dest_key, size, etag, action (move, copy or delete), status
+(planned, done, skipped, unchanged or failed) and error. Src objects in S3 are deleted in batches
+of up to 1000 after their copies have been verified, so they can be reported some time after
+they're copied.
+
+A dest template builds each dest key from values of the src object in braces: {key} is the key
+part trailing <src-url>, {dirname} is that up to and including the last /, {basename} is the rest,
+{stem} and {ext} split that at the last dot, and {size}, {etag} and {last_modified} are as listed.
+{last_modified:%Y/%m/%d} formats the time with %Y, %m, %d, %H, %M and %S. Groups of the regex
+given to --src-filter can be used as {1} or {name}. Helpers follow a |: lower, upper, and hash:<n>
+for the first n hex digits of the MD5 of the value. For example,
+dt={last_modified:%Y-%m-%d}/{basename} partitions objects by day, and {key|hash:2}/{key} spreads
+them over 256 prefixes. An object the template needs a value of that isn't known, like the ETag of
+a local file or a group that didn't match its key, is reported as failed and left in place.
+
+With --dest-replace or --dest-template, all src keys are listed before anything is moved, to find
+any that map to the same dest key. The first one listed keeps the dest key. What happens to the
+others depends on the --on-conflict policy: skip skips them, skip-if-same skips those with the same
+size and ETag as the first, and suffix gives them free keys as usual. Otherwise nothing is moved.
+
+With --sync, dest is listed alongside src, and objects are only copied if they're missing from
+dest or differ from what's there. They differ if their sizes do, or if their ETags do and the src
+object is newer, since copies of the same data don't always get the same ETag. Src objects are
+never deleted.
+
+Objects can also be selected by size, age, storage class and key. Only those that pass every
+selection option given are moved. Globs are matched against the key part trailing <src-url>, where
+* and ? don't match /, but ** does, and **/ matches no directories as well as any number. Times
+are UTC dates like 2018-06-01, times like 2018-06-01T12:00:00Z, or ages like 90d or 12h. Sizes
+are in bytes, or may end in K, M, G or T.
+
+A manifest can name the src objects instead of listing them, which is much faster for large
+buckets. It may be an S3 Inventory manifest.json with CSV or Parquet data files, a .csv file of
+bucket and URL-encoded key, as S3 Batch Operations takes, or a file with one key per line. Key lists
+may be gzipped. Objects outside <src-url> are left out. Keys without sizes are looked up one by one,
+by the worker that moves them unless they have to be looked up to be selected, compared or given a
+dest key. Keys that aren't in src are reported as failed. Inventories in ORC aren't supported.
 
 Usage:
-  s3-bulk-move [options] <src-url> <dest-url>
+  s3-bulk-move [options] [--metadata=<pair>]... [--tag=<pair>]... [--src-storage-class=<class>]... [--include=<glob>]... [--exclude=<glob>]... <src-url> <dest-url>
   s3-bulk-move (-h | --help)
   s3-bulk-move --version
 
 Options:
   -h --help       Show this screen.
   --version       Show version.
-  --src-filter    Regex matched against src key parts trailing <src-url>.
-  --dest-replace  Replacement pattern for the trailing key part. May refer to groups from
-                  --src-filter
-  --src-region=<region>    AWS region of src bucket. Defaults to $AWS_DEFAULT_REGION.
-  --dest-region=<region>   AWS region of dest bucket. Defaults to $AWS_DEFAULT_REGION.
+  --src-filter=<regex>     Only move keys whose part trailing <src-url> matches this regex.
+  --dest-replace=<pattern>
+                           Replacement for the trailing key part, appended to <dest-url>. May refer
+                           to groups from --src-filter as $1 or ${name}. Requires --src-filter.
+  --dest-template=<template>
+                           Template for the trailing key part, appended to <dest-url>. Can't be
+                           used with --dest-replace.
+  --include=<glob>         Only move keys matching this glob. May be repeated to match any of them.
+  --exclude=<glob>         Don't move keys matching this glob. May be repeated.
+  --min-size=<size>        Only move objects at least this big.
+  --max-size=<size>        Only move objects at most this big.
+  --modified-before=<time>
+                           Only move objects last modified before this time, or more than this
+                           long ago.
+  --modified-after=<time>
+                           Only move objects last modified at or after this time, or at most this
+                           long ago.
+  --src-storage-class=<class>
+                           Only move objects in this storage class, e.g. STANDARD or GLACIER. May
+                           be repeated to match any of them. Needs an S3 <src-url>.
+  --manifest=<url>         Move the src objects named in this file or S3 object instead of listing
+                           the src.
+  --src-region=<region>    AWS region of src bucket. Defaults to the region in <src-url>, if any,
+                           then $AWS_DEFAULT_REGION.
+  --dest-region=<region>   AWS region of dest bucket. Defaults to the region in <dest-url>, if any,
+                           then $AWS_DEFAULT_REGION.
+  --src-endpoint=<url>     Send src requests to this URL instead of AWS, e.g. an S3-compatible
+                           store like MinIO.
+  --dest-endpoint=<url>    Send dest requests to this URL instead of AWS.
+  --src-profile=<name>     Credentials profile for the src bucket. Defaults to the standard
+                           credential chain.
+  --dest-profile=<name>    Credentials profile for the dest bucket.
+  --src-role-arn=<arn>     Role to assume for the src bucket.
+  --dest-role-arn=<arn>    Role to assume for the dest bucket.
+                           If src and dest use different endpoints, profiles or roles, objects are
+                           downloaded from src and uploaded to dest instead of being copied
+                           server-side.
+  --all-versions           Move every version of each src object, oldest first, so dest keeps the
+                           object history if it has versioning enabled. Src versions are deleted
+                           for good rather than hidden behind a delete marker. Delete markers
+                           themselves aren't moved.
+  --version-id=<id>        Only move the src object version with this ID.
+  --dry-run       Report each planned move without moving anything. Text output shows src and
+                  dest as URLs.
+  --output=<format>        Report format: text, json, jsonl or csv. [default: text]
+  --no-delete     Copy objects to dest and leave the src objects in place.
+  --sync                   Only copy objects that are missing or different at dest.
+  --delete-extraneous      With --sync, also delete dest objects under <dest-url> that no src
+                           object maps to, once everything has been copied.
+  --on-conflict=<policy>   What to do when the dest key is taken: overwrite, skip, skip-if-same,
+                           fail or suffix. skip-if-same treats an object with the same size and
+                           ETag as already copied, and overwrites any other. suffix copies to the
+                           first free key made by adding -1, -2 and so on before the extension.
+                           With --all-versions, the copy of an earlier version of the same src
+                           object isn't a conflict. [default: overwrite]
+  --concurrency=<n>        Number of objects to move in parallel. [default: 16]
+  --start-after=<key>      Only move src keys that sort after this key. Objects finish out of key
+                           order, so use --journal rather than this to resume an interrupted run.
+  --journal=<path>         Record each completed copy and delete in this file, and skip objects it
+                           shows were already moved. Rerun with the same journal to resume.
+  --max-retries=<n>        Times to retry a throttled or failed request before giving up. Errors
+                           that can't succeed on retry, like AccessDenied, are never retried.
+                           [default: 8]
+  --retry-delay=<ms>       Base delay for exponential backoff between retries. [default: 100]
+  --storage-class=<class>  Storage class of dest objects, e.g. STANDARD_IA or GLACIER_IR.
+  --sse=<algorithm>        Encrypt dest objects with AES256 or aws:kms.
+  --sse-kms-key-id=<id>    KMS key for --sse=aws:kms. Defaults to the account's S3 key.
+  --acl=<acl>              Canned ACL for dest objects, e.g. bucket-owner-full-control.
+  --metadata-directive=<directive>
+                           COPY to keep the src user metadata on copies within S3, or REPLACE to
+                           replace it with --metadata. [default: COPY]
+  --metadata=<pair>        User metadata for dest objects, as key=value. May be repeated. Copies
+                           within S3 need --metadata-directive=REPLACE for it to apply.
+  --tagging-directive=<directive>
+                           COPY to keep the src tags on copies within S3, REPLACE to replace them
+                           with --tag, or MERGE to add --tag to them. [default: COPY]
+  --tag=<pair>             Tag for dest objects, as key=value. May be repeated. Copies within S3
+                           need --tagging-directive=REPLACE or MERGE for it to apply.
+  --preserve-parts         Copy multipart src objects within S3 in the parts they were uploaded in,
+                           so the copies keep the src ETag and it can be checked. Objects over
+                           5 GiB always are. Takes a request per part rather than one per object.
 ";
 
+/// The error reported for a key named by a manifest that isn't in src.
+const NOT_IN_SRC: &'static str = "it's in the manifest but not in src";
+
 #[derive(Debug, Deserialize)]
 struct Args {
-    arg_src_url: S3Url,
-    arg_dest_url: S3Url,
+    arg_src_url: Location,
+    arg_dest_url: Location,
     flag_src_filter: Option<String>,
     flag_dest_replace: Option<String>,
     flag_src_region: Option<String>,
     flag_dest_region: Option<String>,
+    flag_src_endpoint: Option<String>,
+    flag_dest_endpoint: Option<String>,
+    flag_src_profile: Option<String>,
+    flag_dest_profile: Option<String>,
+    flag_src_role_arn: Option<String>,
+    flag_dest_role_arn: Option<String>,
+    flag_dry_run: bool,
+    flag_no_delete: bool,
+    flag_concurrency: usize,
+    flag_start_after: Option<String>,
+    flag_journal: Option<String>,
+    flag_max_retries: u32,
+    flag_retry_delay: u64,
+    flag_storage_class: Option<String>,
+    flag_sse: Option<String>,
+    flag_sse_kms_key_id: Option<String>,
+    flag_acl: Option<String>,
+    flag_metadata_directive: String,
+    flag_metadata: Vec<String>,
+    flag_tagging_directive: String,
+    flag_tag: Vec<String>,
+    flag_preserve_parts: bool,
+    flag_all_versions: bool,
+    flag_version_id: Option<String>,
+    flag_output: String,
+    flag_on_conflict: String,
+    flag_sync: bool,
+    flag_delete_extraneous: bool,
+    flag_include: Vec<String>,
+    flag_exclude: Vec<String>,
+    flag_min_size: Option<String>,
+    flag_max_size: Option<String>,
+    flag_modified_before: Option<String>,
+    flag_modified_after: Option<String>,
+    flag_src_storage_class: Vec<String>,
+    flag_manifest: Option<Location>,
+    flag_dest_template: Option<String>,
+}
+
+fn main() {
+    run().err().map(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    });
+}
+
+/// Builds the client settings for one end of the move. A region given on the command line
+/// wins over one found in the URL.
+fn client_config(location: &Location, region: &Option<String>, endpoint: &Option<String>,
+                 profile: &Option<String>, role_arn: &Option<String>)
+                 -> Result<ClientConfig, BoxError> {
+    let url_region = match *location {
+        Location::S3(ref url) => url.region.as_ref(),
+        Location::Local(_) => None,
+    };
+    Ok(ClientConfig {
+        region: client::region(region.as_ref().or(url_region).map(String::as_str),
+                               endpoint.as_ref().map(String::as_str))?,
+        profile: profile.clone(),
+        role_arn: role_arn.clone(),
+    })
+}
+
+fn write_options(args: &Args) -> Result<WriteOptions, BoxError> {
+    match args.flag_sse.as_ref().map(String::as_str) {
+        None | Some("AES256") | Some("aws:kms") => {}
+        Some(sse) => return Err(format!("--sse must be AES256 or aws:kms, not {}", sse).into()),
+    }
+    if args.flag_sse_kms_key_id.is_some() && args.flag_sse.as_ref().map(String::as_str) != Some("aws:kms") {
+        return Err("--sse-kms-key-id requires --sse=aws:kms".into());
+    }
+
+    let replace_metadata = match args.flag_metadata_directive.as_str() {
+        "COPY" => false,
+        "REPLACE" => true,
+        other => return Err(format!("--metadata-directive must be COPY or REPLACE, not {}", other).into()),
+    };
+    let copies_in_s3 = match (&args.arg_src_url, &args.arg_dest_url) {
+        (&Location::S3(_), &Location::S3(_)) => true,
+        _ => false,
+    };
+    if copies_in_s3 && !replace_metadata && !args.flag_metadata.is_empty() {
+        return Err("--metadata requires --metadata-directive=REPLACE".into());
+    }
+
+    let tagging_directive = match args.flag_tagging_directive.as_str() {
+        "COPY" => TaggingDirective::Copy,
+        "REPLACE" => TaggingDirective::Replace,
+        "MERGE" => TaggingDirective::Merge,
+        other => return Err(format!("--tagging-directive must be COPY, REPLACE or MERGE, not {}", other).into()),
+    };
+    if copies_in_s3 && tagging_directive == TaggingDirective::Copy && !args.flag_tag.is_empty() {
+        return Err("--tag requires --tagging-directive=REPLACE or MERGE".into());
+    }
+
+    Ok(WriteOptions {
+        storage_class: args.flag_storage_class.clone(),
+        sse: args.flag_sse.clone(),
+        sse_kms_key_id: args.flag_sse_kms_key_id.clone(),
+        acl: args.flag_acl.clone(),
+        replace_metadata,
+        metadata: parse_pairs("--metadata", &args.flag_metadata)?,
+        tagging_directive,
+        tags: parse_pairs("--tag", &args.flag_tag)?,
+        preserve_parts: args.flag_preserve_parts,
+    })
+}
+
+fn parse_pairs(flag: &str, pairs: &[String]) -> Result<HashMap<String, String>, BoxError> {
+    let mut map = HashMap::new();
+    for kv in pairs {
+        let mut parts = kv.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) if !k.is_empty() => map.insert(k.to_owned(), v.to_owned()),
+            _ => return Err(format!("{} must look like key=value, not {}", flag, kv).into()),
+        };
+    }
+    Ok(map)
+}
+
+fn object_filter(args: &Args) -> Result<ObjectFilter, BoxError> {
+    if let (false, &Location::Local(_)) = (args.flag_src_storage_class.is_empty(), &args.arg_src_url) {
+        return Err("--src-storage-class needs an S3 <src-url>".into());
+    }
+    Ok(ObjectFilter {
+        min_size: parse_opt(&args.flag_min_size, filter::parse_size)?,
+        max_size: parse_opt(&args.flag_max_size, filter::parse_size)?,
+        modified_before: parse_opt(&args.flag_modified_before, filter::parse_time)?,
+        modified_after: parse_opt(&args.flag_modified_after, filter::parse_time)?,
+        storage_classes: args.flag_src_storage_class.clone(),
+        include: args.flag_include.iter().map(|g| filter::glob(g)).collect::<Result<_, _>>()?,
+        exclude: args.flag_exclude.iter().map(|g| filter::glob(g)).collect::<Result<_, _>>()?,
+    })
+}
+
+fn parse_opt<T>(value: &Option<String>, parse: fn(&str) -> Result<T, BoxError>) -> Result<Option<T>, BoxError> {
+    match *value {
+        Some(ref value) => parse(value).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn output_format(format: &str) -> Result<output::Format, BoxError> {
+    match format {
+        "text" => Ok(output::Format::Text),
+        "json" => Ok(output::Format::Json),
+        "jsonl" => Ok(output::Format::Jsonl),
+        "csv" => Ok(output::Format::Csv),
+        other => Err(format!("--output must be text, json, jsonl or csv, not {}", other).into()),
+    }
 }
 
-#[derive(Debug)]
-struct S3Url {
-    bucket: String,
-    prefix: Option<String>,
+fn on_conflict(policy: &str) -> Result<OnConflict, BoxError> {
+    match policy {
+        "overwrite" => Ok(OnConflict::Overwrite),
+        "skip" => Ok(OnConflict::Skip),
+        "skip-if-same" => Ok(OnConflict::SkipIfSame),
+        "fail" => Ok(OnConflict::Fail),
+        "suffix" => Ok(OnConflict::Suffix),
+        other => Err(format!("--on-conflict must be overwrite, skip, skip-if-same, fail or suffix, not {}", other).into()),
+    }
 }
 
-struct S3UrlVisitor;
+/// Finds src keys that map to the same dest key, given the whole plan. The first src key listed
+/// keeps the dest key, and `on_conflict` decides whether the others are skipped, left to be
+/// given suffixed keys, or fail the move. Returns the jobs left to run and the skipped ops.
+fn resolve_collisions(jobs: Vec<Vec<MoveOp>>, on_conflict: OnConflict)
+                      -> Result<(Vec<Vec<MoveOp>>, Vec<(MoveOp, String)>), BoxError> {
+    let mut first: HashMap<String, (String, Option<i64>, Option<String>)> = HashMap::new();
+    let mut kept = Vec::with_capacity(jobs.len());
+    let mut skipped = Vec::new();
+    let mut collisions = Vec::new();
+
+    for job in jobs {
+        let mut keep = Vec::with_capacity(job.len());
+        for op in job {
+            let reason = match first.get(&op.dest_key) {
+                // Versions of the same key all go to the same dest key.
+                Some(&(ref src_key, _, _)) if *src_key == op.src_key => None,
+                Some(&(ref src_key, size, ref etag)) => {
+                    let same = size == op.size && etag.is_some() && *etag == op.etag;
+                    match on_conflict {
+                        OnConflict::Suffix => None,
+                        OnConflict::Skip => Some(format!("{} is also the dest of {}", op.dest_key, src_key)),
+                        OnConflict::SkipIfSame if same => Some(format!("{} is the same as {}", op.src_key, src_key)),
+                        _ => {
+                            collisions.push(format!("{} and {} both map to {}", src_key, op.src_key, op.dest_key));
+                            None
+                        }
+                    }
+                }
+                None => {
+                    first.insert(op.dest_key.clone(), (op.src_key.clone(), op.size, op.etag.clone()));
+                    None
+                }
+            };
+            match reason {
+                Some(reason) => skipped.push((op, reason)),
+                None => keep.push(op),
+            }
+        }
+        if !keep.is_empty() {
+            kept.push(keep);
+        }
+    }
 
-impl<'de> de::Visitor<'de> for S3UrlVisitor {
-    type Value = S3Url;
+    if !collisions.is_empty() {
+        let shown = collisions.iter().take(10).cloned().collect::<Vec<_>>().join("\n");
+        return Err(format!("{} src keys map to a dest key that's already taken, so nothing was moved:\n{}{}",
+                           collisions.len(),
+                           shown,
+                           if collisions.len() > 10 { "\n..." } else { "" }).into());
+    }
+    Ok((kept, skipped))
+}
 
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a URL like s3://bucket/some/key")
+/// True if `dest` is already an up-to-date copy of the src object of `op`: the same size, and
+/// either the same ETag or no older than the src object, since a different part size or
+/// encryption gives the same data a different ETag.
+fn is_unchanged(op: &MoveOp, dest: &Listed) -> bool {
+    if op.size != Some(dest.size) {
+        return false;
+    }
+    if op.etag.is_some() && op.etag == dest.etag {
+        return true;
     }
+    match (op.last_modified, dest.last_modified) {
+        (Some(src), Some(dest)) => src <= dest,
+        _ => false,
+    }
+}
 
-    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: de::Error {
-        lazy_static! {
-            static ref S3_URL_REGEX: Regex = Regex::new("^s3://([^/]+)/(.*)").unwrap();
+/// Lists everything under `location` in dest, keyed by key. A local dest that doesn't exist
+/// yet is empty.
+fn list_dest(mover: &Mover<S3Client>, location: &Location) -> Result<HashMap<String, Listed>, BoxError> {
+    let mut objects = HashMap::new();
+    {
+        let add = |obj: Listed| {
+            objects.insert(obj.key.clone(), obj);
+            Ok(true)
+        };
+        match mover.dest {
+            Store::S3(ref dest) => listing::list_s3(&dest.client, &mover.retry, &dest.name, location.prefix(), None, add)?,
+            Store::Local(ref root) if !root.exists() => {}
+            Store::Local(ref root) => listing::list_local(root, None, add)?,
         }
+    }
+    Ok(objects)
+}
 
-        let matches = S3_URL_REGEX
-            .captures(v)
-            .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(v), &self))?;
-        let prefix = matches.get(2).unwrap().as_str().to_owned();
-        Ok(S3Url {
-            bucket: matches.get(1).unwrap().as_str().to_owned(),
-            prefix: if prefix.len() > 0 { Some(prefix) } else { None },
-        })
+/// A src object named by a manifest.
+enum ManifestObject {
+    /// Found in the manifest, or by looking its key up.
+    Listed(Listed),
+    /// A key left for the worker that moves it to look up.
+    Key(String),
+    /// A key that isn't in src.
+    Missing(String),
+}
+
+/// Finds the src object a manifest entry names, or returns None if it's outside `src`, not after
+/// `start_after`, or already moved according to the journal. Entries without a size are looked up
+/// in src if `lookup` is true, and otherwise returned as keys.
+fn manifest_object(mover: &Mover<S3Client>, src: &Location, start_after: Option<&str>, lookup: bool, entry: Entry)
+                   -> Result<Option<ManifestObject>, BoxError> {
+    {
+        let (bucket, key) = match entry {
+            Entry::Key { ref bucket, ref key } => (bucket.as_ref(), key),
+            Entry::Object { ref bucket, ref object } => (Some(bucket), &object.key),
+        };
+        if let (Some(bucket), &Location::S3(ref url)) = (bucket, src) {
+            if *bucket != url.bucket {
+                return Err(format!("the manifest names s3://{}/{}, which isn't in the src bucket", bucket, key).into());
+            }
+        }
+        if !key.starts_with(src.prefix().unwrap_or("")) || start_after.map_or(false, |start_after| key.as_str() <= start_after) {
+            return Ok(None);
+        }
+    }
+
+    match entry {
+        Entry::Object { object, .. } => Ok(Some(ManifestObject::Listed(object))),
+        Entry::Key { key, .. } if !lookup => Ok(Some(ManifestObject::Key(key))),
+        Entry::Key { key, .. } => {
+            // Checked again once the object is planned, but this saves looking up keys that are done.
+            if mover.journal.as_ref().map_or(false, |j| j.is_moved(&key, None)) {
+                return Ok(None);
+            }
+            match mover.find_src(&key)? {
+                Some(obj) => Ok(Some(ManifestObject::Listed(obj))),
+                None => Ok(Some(ManifestObject::Missing(key))),
+            }
+        }
     }
 }
 
-impl<'de> Deserialize<'de> for S3Url {
-    fn deserialize<D>(d: D) -> Result<S3Url, D::Error> where D: Deserializer<'de> {
-        d.deserialize_str(S3UrlVisitor)
+fn record(op: MoveOp, action: Action, status: Status, error: Option<String>) -> Record {
+    Record {
+        src_key: op.src_key,
+        version_id: op.version_id,
+        dest_key: op.dest_key,
+        size: op.size,
+        etag: op.etag,
+        action,
+        status,
+        error,
     }
 }
 
-fn main() {
-    run().err().map(|err| {
-        println!("{}", err);
-        std::process::exit(1)
-    });
+/// Reports each finished or skipped move, each manifest key that isn't in src, and each src object
+/// that was copied but couldn't be deleted. Returns the number of those last ones.
+fn report(output: &Output, action: Action, completed: Completed) -> Result<usize, BoxError> {
+    let failed = completed.failed.len();
+    for op in completed.moved {
+        output.write(&record(op, action, Status::Done, None))?;
+    }
+    for (op, reason) in completed.skipped {
+        output.write(&record(op, action, Status::Skipped, Some(reason)))?;
+    }
+    for op in completed.missing {
+        output.write(&record(op, action, Status::Failed, Some(NOT_IN_SRC.to_owned())))?;
+    }
+    for (op, err) in completed.failed {
+        output.write(&record(op, action, Status::Failed, Some(format!("copied but not deleted: {}", err))))?;
+    }
+    Ok(failed)
 }
 
-fn get_region(region_flag: &Option<String>, default_region: &String) -> Result<Region, impl std::error::Error> {
-    Region::from_str(
-        region_flag.as_ref().unwrap_or(default_region).as_str()
-    )
+fn store(location: &Location, config: &ClientConfig) -> Result<Store<S3Client>, BoxError> {
+    match *location {
+        Location::S3(ref url) => Ok(Store::S3(Bucket { client: config.s3_client()?, name: url.bucket.clone() })),
+        Location::Local(ref path) => Ok(Store::Local(path.clone())),
+    }
 }
 
-fn run() -> Result<(), Box<std::error::Error>> {
+fn run() -> Result<(), BoxError> {
     let args: Args = docopt::Docopt::new(USAGE)
         .and_then(|d| d.deserialize())?;
 
-    let default_region = std::env::var("AWS_DEFAULT_REGION")
-        .unwrap_or("us-east-1".to_owned());
-    let src_region = get_region(&args.flag_src_region, &default_region)?;
-    let dest_region = get_region(&args.flag_dest_region, &default_region)?;
-
-    let src_client = S3Client::simple(src_region);
-    let dest_client = S3Client::simple(dest_region);
-
-    let src_filter = args.flag_src_filter.map(|s| Regex::new(s.as_str()).unwrap());
-    let dest_replace = args.flag_dest_replace.as_ref().map(String::as_str);
-
-    let mut list_req = rusoto_s3::ListObjectsRequest::default();
-    list_req.bucket = args.arg_src_url.bucket;
-    list_req.prefix = args.arg_src_url.prefix;
-    let mut next_marker = None;
-
-    loop {
-        list_req.marker = next_marker;
-        let rsp = src_client.list_objects(&list_req).sync()?;
-        if rsp.contents.is_none() || rsp.contents.as_ref().unwrap().is_empty() {
-            break;
-        }
-
-        let mut objs: Box<Iterator<Item = (String, i64)>> = Box::new(rsp.contents.as_ref().unwrap().iter()
-            .filter(|obj| obj.key.is_some() && obj.size.is_some())
-            .map(|obj| {
-                let mut key = obj.key.as_ref().unwrap().clone();
-                let pfx_len = list_req.prefix
-                    .as_ref()
-                    .map(|pfx| pfx.as_str().len())
-                    .unwrap_or(0);
-                (key.split_off(pfx_len), obj.size.unwrap())
-            }));
-
-        if src_filter.is_some() {
-            if dest_replace.is_some() {
-                objs = Box::new(objs.filter_map(|(key, sz)| {
-                    let re = src_filter.as_ref().unwrap();
-                    let rep = dest_replace.unwrap();
-                    re.captures(key.as_str())
-                        .map(|x| {
-                            let mut result = String::new();
-                            x.expand(rep, &mut result);
-                            (result, sz)
-                        })
-                }))
+    if args.flag_concurrency == 0 {
+        return Err("--concurrency must be at least 1".into());
+    }
+    if let (&Location::Local(_), &Location::Local(_)) = (&args.arg_src_url, &args.arg_dest_url) {
+        return Err("<src-url> and <dest-url> can't both be local paths".into());
+    }
+    let versions = args.flag_all_versions || args.flag_version_id.is_some();
+    if let (true, &Location::Local(_)) = (versions, &args.arg_src_url) {
+        return Err("--all-versions and --version-id need an S3 <src-url>".into());
+    }
+    if args.flag_sync && versions {
+        return Err("--sync can't be used with --all-versions or --version-id".into());
+    }
+    if args.flag_sync && args.flag_on_conflict == "suffix" {
+        return Err("--sync can't be used with --on-conflict=suffix".into());
+    }
+    if args.flag_delete_extraneous && !args.flag_sync {
+        return Err("--delete-extraneous requires --sync".into());
+    }
+    if args.flag_manifest.is_some() && versions {
+        return Err("--manifest can't be used with --all-versions or --version-id".into());
+    }
+    if args.flag_delete_extraneous && args.flag_manifest.is_some() {
+        return Err("--delete-extraneous can't be used with --manifest, which may leave src keys out".into());
+    }
+    if args.flag_delete_extraneous && args.flag_start_after.is_some() {
+        return Err("--delete-extraneous can't be used with --start-after, which leaves src keys unlisted".into());
+    }
+    if args.flag_delete_extraneous && args.flag_src_filter.is_some()
+        && (args.flag_dest_replace.is_some() || args.flag_dest_template.is_some()) {
+        return Err("--delete-extraneous can't be used with --src-filter and --dest-replace or --dest-template, \
+                    which leave the dest keys of filtered-out src keys unknown".into());
+    }
+    let rewriter = KeyRewriter::new(args.flag_src_filter.as_ref().map(String::as_str),
+                                    args.flag_dest_replace.as_ref().map(String::as_str),
+                                    args.flag_dest_template.as_ref().map(String::as_str))?;
+    let selection = object_filter(&args)?;
+
+    // A dry run still skips what the journal shows was moved, but mustn't touch the file.
+    let journal = match args.flag_journal {
+        Some(ref path) if args.flag_dry_run => Some(Journal::open_read_only(path)?),
+        Some(ref path) => Some(Journal::open(path)?),
+        None => None,
+    };
+
+    let src_config = client_config(&args.arg_src_url, &args.flag_src_region, &args.flag_src_endpoint,
+                                   &args.flag_src_profile, &args.flag_src_role_arn)?;
+    let dest_config = client_config(&args.arg_dest_url, &args.flag_dest_region, &args.flag_dest_endpoint,
+                                    &args.flag_dest_profile, &args.flag_dest_role_arn)?;
+    // Server-side copies are sent to the dest endpoint and authorized with the dest credentials
+    // alone, so they only work when the dest store holds src too and those credentials can read it.
+    let stream = args.flag_src_endpoint != args.flag_dest_endpoint
+        || src_config.profile != dest_config.profile
+        || src_config.role_arn != dest_config.role_arn;
+
+    // Manifests and inventory reports are read with the src credentials.
+    let manifest = match args.flag_manifest {
+        Some(ref location) => Some((location, src_config.s3_client()?)),
+        None => None,
+    };
+
+    let mover = Arc::new(Mover {
+        src: store(&args.arg_src_url, &src_config)?,
+        dest: store(&args.arg_dest_url, &dest_config)?,
+        journal,
+        retry: RetryPolicy {
+            max_retries: args.flag_max_retries,
+            base_delay: Duration::from_millis(args.flag_retry_delay),
+        },
+        delete_src: !args.flag_no_delete && !args.flag_sync,
+        stream,
+        write: write_options(&args)?,
+        pending_deletes: Mutex::new(Vec::new()),
+        on_conflict: on_conflict(&args.flag_on_conflict)?,
+        claimed: Mutex::new(HashMap::new()),
+    });
+    let output = Arc::new(Output::new(output_format(&args.flag_output)?,
+                                      args.arg_src_url.clone(),
+                                      args.arg_dest_url.clone()));
+    let action = if mover.delete_src { Action::Move } else { Action::Copy };
+    let failed_deletes = Arc::new(AtomicUsize::new(0));
+    let missing = Arc::new(AtomicUsize::new(0));
+
+    let pool = {
+        let mover = mover.clone();
+        let output = output.clone();
+        let failed_deletes = failed_deletes.clone();
+        let missing = missing.clone();
+        // Versions of a key are moved one after another, oldest first, so they're created at dest
+        // in the same order.
+        WorkerPool::new(args.flag_concurrency, args.flag_concurrency * 4, move |ops: Vec<MoveOp>| {
+            for op in ops {
+                match mover.move_object(op.clone()) {
+                    Ok(completed) => {
+                        missing.fetch_add(completed.missing.len(), Ordering::SeqCst);
+                        failed_deletes.fetch_add(report(&output, action, completed)?, Ordering::SeqCst)
+                    }
+                    Err(err) => {
+                        output.write(&record(op, action, Status::Failed, Some(err.to_string())))?;
+                        return Err(err);
+                    }
+                };
+            }
+            Ok(())
+        })
+    };
+
+    let src_prefix_len = args.arg_src_url.prefix().map_or(0, str::len);
+    let dest_prefix = args.arg_dest_url.prefix().unwrap_or("");
+    let start_after = args.flag_start_after.as_ref().map(String::as_str);
+
+    // Dest keys that some listed src object maps to, whether or not it's moved this time, so
+    // their dest objects aren't taken for extraneous ones.
+    let mapped = RefCell::new(HashSet::new());
+    // Selected src objects that --dest-template can't build a dest key for, and why.
+    let unrendered = RefCell::new(Vec::new());
+
+    let plan = |obj: Listed| -> Option<MoveOp> {
+        if args.flag_version_id.is_some() && obj.version_id != args.flag_version_id {
+            return None;
+        }
+        let dest_suffix = match rewriter.rewrite(&obj, &obj.key[src_prefix_len..]) {
+            Ok(Some(dest_suffix)) => dest_suffix,
+            // Without a rewrite, a filtered-out src key still has a dest copy under the same
+            // key, which isn't extraneous.
+            Ok(None) => {
+                if args.flag_delete_extraneous {
+                    mapped.borrow_mut().insert(format!("{}{}", dest_prefix, &obj.key[src_prefix_len..]));
+                }
+                return None;
+            }
+            Err(reason) => {
+                if selection.matches(&obj, &obj.key[src_prefix_len..]) {
+                    unrendered.borrow_mut().push((obj, reason));
+                }
+                return None;
+            }
+        };
+        let dest_key = format!("{}{}", dest_prefix, dest_suffix);
+        if args.flag_delete_extraneous {
+            mapped.borrow_mut().insert(dest_key.clone());
+        }
+        if !selection.matches(&obj, &obj.key[src_prefix_len..]) {
+            return None;
+        }
+        let op = MoveOp {
+            dest_key,
+            src_key: obj.key,
+            size: Some(obj.size),
+            etag: obj.etag,
+            last_modified: obj.last_modified,
+            version_id: obj.version_id,
+        };
+
+        let version_id = op.version_id.as_ref().map(String::as_str);
+        let done = mover.journal.as_ref().map_or(false, |j| {
+            if mover.delete_src {
+                j.is_moved(&op.src_key, version_id)
             } else {
-                objs = Box::new(objs.filter(|(key, sz)| {
-                    let re = src_filter.as_ref().unwrap();
-                    re.is_match(key.as_str())
-                }))
+                j.is_copied(&op.src_key, version_id, &op.dest_key)
             }
+        });
+        if done {
+            None
+        } else {
+            Some(op)
         }
+    };
 
-        for (key, sz) in objs {
-            println!("{}\t{}", key, sz);
+    let dispatch = |ops: Vec<MoveOp>| -> Result<bool, BoxError> {
+        if args.flag_dry_run {
+            for op in ops {
+                output.write(&record(op, action, Status::Planned, None))?;
+            }
+            return Ok(true);
+        }
+        if pool.failed() {
+            return Ok(false);
         }
+        pool.submit(ops)?;
+        Ok(true)
+    };
 
-        if rsp.is_truncated.unwrap() {
-            next_marker = rsp.contents.as_ref().unwrap().last().unwrap().key.clone()
-        } else {
-            break
+    let dest_listing = if args.flag_sync {
+        let mover = mover.clone();
+        let dest_url = args.arg_dest_url.clone();
+        Some(thread::spawn(move || list_dest(&mover, &dest_url)))
+    } else {
+        None
+    };
+
+    // Rewritten keys can collide, so the whole plan is checked before any of it runs. Syncs
+    // need the whole dest listing before they can tell what to copy.
+    let buffer = if args.flag_dest_replace.is_some() || args.flag_dest_template.is_some() || args.flag_sync {
+        Some(RefCell::new(Vec::new()))
+    } else {
+        None
+    };
+    let submit = |ops: Vec<MoveOp>| -> Result<bool, BoxError> {
+        if ops.is_empty() {
+            return Ok(true);
+        }
+        match buffer {
+            Some(ref buffer) => {
+                buffer.borrow_mut().push(ops);
+                Ok(true)
+            }
+            None => dispatch(ops),
+        }
+    };
+
+    // Keys a manifest names without a size are looked up by the worker that moves them, unless
+    // planning needs more of the object than its key: to select it by size, age or storage class,
+    // to compare it with dest or with a src object that maps to the same dest key, or to build its
+    // dest key from. Dry runs look keys up as they're read, so missing ones show up in the plan.
+    let lookup = args.flag_dry_run
+        || args.flag_sync
+        || selection.needs_object()
+        || rewriter.needs_object()
+        || mover.on_conflict == OnConflict::SkipIfSame;
+
+    let listed = if let Some((location, ref client)) = manifest {
+        manifest::read(client, &mover.retry, location, |entry| {
+            match manifest_object(&mover, &args.arg_src_url, start_after, lookup, entry)? {
+                Some(ManifestObject::Listed(obj)) => submit(plan(obj).into_iter().collect()),
+                Some(ManifestObject::Key(key)) => {
+                    // Planning only uses the key here, so the object's other values can be left
+                    // out until the worker looks it up.
+                    let obj = Listed {
+                        key,
+                        size: 0,
+                        etag: None,
+                        last_modified: None,
+                        version_id: None,
+                        storage_class: None,
+                    };
+                    submit(plan(obj).into_iter().map(|op| MoveOp { size: None, ..op }).collect())
+                }
+                Some(ManifestObject::Missing(key)) => {
+                    missing.fetch_add(1, Ordering::SeqCst);
+                    output.write(&Record {
+                        src_key: key,
+                        version_id: None,
+                        dest_key: String::new(),
+                        size: None,
+                        etag: None,
+                        action,
+                        status: Status::Failed,
+                        error: Some(NOT_IN_SRC.to_owned()),
+                    })?;
+                    Ok(true)
+                }
+                None => Ok(true),
+            }
+        })
+    } else {
+        match mover.src {
+            Store::S3(ref src) if versions => {
+                listing::list_s3_versions(&src.client, &mover.retry, &src.name, args.arg_src_url.prefix(), start_after,
+                                          |versions| submit(versions.into_iter().filter_map(&plan).collect()))
+            }
+            Store::S3(ref src) => {
+                listing::list_s3(&src.client, &mover.retry, &src.name, args.arg_src_url.prefix(), start_after,
+                                 |obj| submit(plan(obj).into_iter().collect()))
+            }
+            Store::Local(ref root) => listing::list_local(root, start_after, |obj| submit(plan(obj).into_iter().collect())),
+        }
+    };
+    // Returns the dest objects that no src object maps to, when syncing.
+    let listed = listed.and_then(|()| -> Result<Vec<Listed>, BoxError> {
+        let jobs = match buffer {
+            Some(buffer) => buffer.into_inner(),
+            None => return Ok(Vec::new()),
+        };
+        for &(ref obj, ref reason) in unrendered.borrow().iter() {
+            output.write(&Record {
+                src_key: obj.key.clone(),
+                version_id: obj.version_id.clone(),
+                dest_key: String::new(),
+                size: Some(obj.size),
+                etag: obj.etag.clone(),
+                action,
+                status: Status::Failed,
+                error: Some(format!("can't build a dest key from --dest-template: {}", reason)),
+            })?;
+        }
+        let (mut jobs, skipped) = resolve_collisions(jobs, mover.on_conflict)?;
+        for (op, reason) in skipped {
+            output.write(&record(op, action, Status::Skipped, Some(reason)))?;
+        }
+
+        let mut extraneous = Vec::new();
+        if let Some(dest_listing) = dest_listing {
+            let dest_objects = dest_listing.join().map_err(|_| "dest listing thread panicked")??;
+            let mut changed = Vec::with_capacity(jobs.len());
+            for op in jobs.into_iter().flatten() {
+                match dest_objects.get(&op.dest_key) {
+                    Some(dest) if is_unchanged(&op, dest) => {
+                        output.write(&record(op, action, Status::Unchanged, None))?;
+                    }
+                    _ => changed.push(vec![op]),
+                }
+            }
+            jobs = changed;
+            if args.flag_delete_extraneous {
+                let mapped = mapped.borrow();
+                extraneous = dest_objects.into_iter()
+                    .filter(|&(ref key, _)| !mapped.contains(key))
+                    .map(|(_, obj)| obj)
+                    .collect();
+                extraneous.sort_by(|a, b| a.key.cmp(&b.key));
+            }
+        }
+
+        for job in jobs {
+            if !dispatch(job)? {
+                break;
+            }
+        }
+        Ok(extraneous)
+    });
+
+    // Objects that were copied before a failure can still be deleted, and the output is closed
+    // either way so it stays parseable.
+    let joined = pool.join();
+    let flushed = mover.flush_deletes().and_then(|completed| report(&output, action, completed));
+    let extraneous = match (&listed, &joined, &flushed) {
+        (&Ok(ref extraneous), &Ok(()), &Ok(0)) => delete_extraneous(&mover, &output, extraneous, args.flag_dry_run),
+        _ => Ok(0),
+    };
+    output.finish()?;
+    listed?;
+    joined?;
+    failed_deletes.fetch_add(flushed?, Ordering::SeqCst);
+
+    let unrendered = unrendered.into_inner().len();
+    match (failed_deletes.load(Ordering::SeqCst), extraneous?, unrendered, missing.load(Ordering::SeqCst)) {
+        (0, 0, 0, 0) => Ok(()),
+        (0, 0, 0, n) => Err(format!("{} keys in the manifest aren't in src", n).into()),
+        (0, 0, n, _) => Err(format!("--dest-template couldn't be rendered for {} src objects", n).into()),
+        (0, n, _, _) => Err(format!("{} extraneous dest objects couldn't be deleted", n).into()),
+        (n, _, _, _) => Err(format!("{} src objects were copied but not deleted", n).into()),
+    }
+}
+
+/// Deletes dest objects that no src object maps to, or reports the plan to on a dry run.
+/// Returns the number that couldn't be deleted.
+fn delete_extraneous(mover: &Mover<S3Client>, output: &Output, extraneous: &[Listed], dry_run: bool)
+                     -> Result<usize, BoxError> {
+    let delete_record = |obj: &Listed, status, error| Record {
+        src_key: String::new(),
+        version_id: None,
+        dest_key: obj.key.clone(),
+        size: Some(obj.size),
+        etag: obj.etag.clone(),
+        action: Action::Delete,
+        status,
+        error,
+    };
+    if dry_run {
+        for obj in extraneous {
+            output.write(&delete_record(obj, Status::Planned, None))?;
+        }
+        return Ok(0);
+    }
+
+    let deleted = mover.delete_from_dest(extraneous.iter().map(|obj| obj.key.clone()).collect())?;
+    let mut failed = 0;
+    for (obj, (_, error)) in extraneous.iter().zip(deleted) {
+        let status = if error.is_some() { Status::Failed } else { Status::Done };
+        if error.is_some() {
+            failed += 1;
+        }
+        output.write(&delete_record(obj, status, error))?;
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(src_key: &str, dest_key: &str, size: i64, etag: &str) -> MoveOp {
+        MoveOp {
+            src_key: src_key.to_owned(),
+            dest_key: dest_key.to_owned(),
+            size: Some(size),
+            etag: Some(etag.to_owned()),
+            last_modified: Some(1_000),
+            version_id: None,
+        }
+    }
+
+    fn listed(size: i64, etag: &str, last_modified: i64) -> Listed {
+        Listed {
+            key: "dest".to_owned(),
+            size,
+            etag: Some(etag.to_owned()),
+            last_modified: Some(last_modified),
+            version_id: None,
+            storage_class: None,
         }
     }
 
+    fn src_keys(jobs: &[Vec<MoveOp>]) -> Vec<&str> {
+        jobs.iter().flatten().map(|op| op.src_key.as_str()).collect()
+    }
+
+    fn colliding() -> Vec<Vec<MoveOp>> {
+        vec![
+            vec![op("a/x.txt", "x.txt", 1, "\"1\"")],
+            vec![op("b/x.txt", "x.txt", 1, "\"1\"")],
+            vec![op("c/x.txt", "x.txt", 2, "\"2\"")],
+            vec![op("c/y.txt", "y.txt", 2, "\"2\"")],
+        ]
+    }
+
+    #[test]
+    fn resolves_collisions_by_policy() {
+        let (kept, skipped) = resolve_collisions(colliding(), OnConflict::Skip).unwrap();
+        assert_eq!(src_keys(&kept), vec!["a/x.txt", "c/y.txt"]);
+        assert_eq!(skipped.len(), 2);
+
+        let (kept, skipped) = resolve_collisions(colliding(), OnConflict::Suffix).unwrap();
+        assert_eq!(src_keys(&kept).len(), 4);
+        assert!(skipped.is_empty());
 
-    Ok(())
-}
\ No newline at end of file
+        let err = resolve_collisions(colliding(), OnConflict::SkipIfSame).unwrap_err().to_string();
+        assert!(err.contains("a/x.txt and c/x.txt both map to x.txt"), "{}", err);
+        assert!(!err.contains("b/x.txt"), "{}", err);
+
+        assert!(resolve_collisions(colliding(), OnConflict::Overwrite).is_err());
+        assert!(resolve_collisions(colliding(), OnConflict::Fail).is_err());
+    }
+
+    #[test]
+    fn keeps_versions_of_a_key_together() {
+        let mut old = op("a.txt", "a.txt", 1, "\"1\"");
+        old.version_id = Some("1".to_owned());
+        let mut new = op("a.txt", "a.txt", 2, "\"2\"");
+        new.version_id = Some("2".to_owned());
+        let (kept, skipped) = resolve_collisions(vec![vec![old, new]], OnConflict::Fail).unwrap();
+        assert_eq!(kept[0].len(), 2);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn compares_with_dest() {
+        let src = op("a.txt", "a.txt", 5, "\"abc\"");
+        assert!(is_unchanged(&src, &listed(5, "\"abc\"", 0)));
+        assert!(!is_unchanged(&src, &listed(6, "\"abc\"", 2_000)));
+
+        // The dest object may be an SSE-KMS copy, so a different ETag just means it has to be newer.
+        assert!(is_unchanged(&src, &listed(5, "\"abd\"", 1_000)));
+        assert!(!is_unchanged(&src, &listed(5, "\"abd\"", 999)));
+
+        // Multipart ETags can't be compared, so the dest object just has to be newer.
+        let src = op("a.txt", "a.txt", 5, "\"abc-2\"");
+        assert!(is_unchanged(&src, &listed(5, "\"abc\"", 1_000)));
+        assert!(!is_unchanged(&src, &listed(5, "\"abc\"", 999)));
+    }
+}
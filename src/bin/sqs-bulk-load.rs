@@ -1,3 +1,365 @@
+extern crate aws_tools;
+extern crate docopt;
+extern crate rusoto_sqs;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use aws_tools::BoxError;
+use aws_tools::client::{self, ClientConfig};
+use aws_tools::retry::{Retryable, RetryPolicy};
+use rusoto_sqs::{BatchResultErrorEntry, SendMessageBatchError, SendMessageBatchRequest, SendMessageBatchRequestEntry,
+                 SendMessageBatchResult, Sqs};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::time::Duration;
+
+const USAGE: &'static str = "
+Send messages to an SQS queue in batches.
+
+Messages are read from each <file>, or from stdin if no files are given. Each line is one message
+unless --json is given, in which case each input is a JSON array whose elements are the messages.
+String elements are sent as-is; any other element is sent as its JSON encoding. Messages over
+256 KiB, which SQS won't take, are reported as failed and skipped.
+
+Usage:
+  sqs-bulk-load [options] <queue-url> [<file>...]
+  sqs-bulk-load (-h | --help)
+  sqs-bulk-load --version
+
+Options:
+  -h --help          Show this screen.
+  --version          Show version.
+  --json             Read each input as a JSON array of messages.
+  --region=<region>  AWS region of the queue. Defaults to $AWS_DEFAULT_REGION.
+  --endpoint=<url>   Send requests to this URL instead of AWS, e.g. a local SQS stand-in.
+  --max-retries=<n>  Times to retry a throttled or failed request before giving up. Messages
+                     SQS rejects as invalid are never retried. [default: 8]
+  --retry-delay=<ms>  Base delay for exponential backoff between retries. [default: 100]
+";
+
+/// SendMessageBatch accepts at most 10 entries per call.
+const MAX_BATCH_LEN: usize = 10;
+/// SendMessageBatch also caps the combined size of all message bodies in a call at 256 KiB.
+const MAX_BATCH_BYTES: usize = 256 * 1024;
+/// The largest message SQS accepts.
+const MAX_MESSAGE_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_queue_url: String,
+    arg_file: Vec<String>,
+    flag_json: bool,
+    flag_region: Option<String>,
+    flag_endpoint: Option<String>,
+    flag_max_retries: u32,
+    flag_retry_delay: u64,
+}
+
+/// Accumulates messages and sends them with SendMessageBatch once a batch is full. `send` makes
+/// the call, which is left to the caller so that tests can stand in for SQS.
+struct Batcher<'a, F> {
+    send: F,
+    queue_url: &'a str,
+    retry: RetryPolicy,
+    entries: Vec<SendMessageBatchRequestEntry>,
+    bytes: usize,
+    next_id: usize,
+    sent: usize,
+    failed: usize,
+}
+
+/// Why a batch wasn't sent in full: the call failed, or SQS failed some entries through no fault
+/// of the sender, say because it was throttled. Either way, whatever wasn't sent may be retried.
+#[derive(Debug)]
+enum FlushError {
+    Request(SendMessageBatchError),
+    Unsent,
+}
+
+impl Retryable for FlushError {
+    fn is_retryable(&self) -> bool {
+        match *self {
+            FlushError::Request(ref err) => err.is_retryable(),
+            FlushError::Unsent => true,
+        }
+    }
+}
+
+impl<'a, F> Batcher<'a, F>
+    where F: FnMut(&SendMessageBatchRequest) -> Result<SendMessageBatchResult, SendMessageBatchError> {
+    fn new(send: F, queue_url: &'a str, retry: RetryPolicy) -> Batcher<'a, F> {
+        Batcher {
+            send,
+            queue_url,
+            retry,
+            entries: Vec::with_capacity(MAX_BATCH_LEN),
+            bytes: 0,
+            next_id: 0,
+            sent: 0,
+            failed: 0,
+        }
+    }
+
+    fn push(&mut self, body: String) -> Result<(), BoxError> {
+        if body.len() > MAX_MESSAGE_BYTES {
+            eprintln!("message {} failed: it's {} bytes, over the {} byte limit", self.next_id, body.len(),
+                      MAX_MESSAGE_BYTES);
+            self.failed += 1;
+            self.next_id += 1;
+            return Ok(());
+        }
+        if !self.entries.is_empty() && self.bytes + body.len() > MAX_BATCH_BYTES {
+            self.flush()?;
+        }
+
+        self.bytes += body.len();
+        self.entries.push(SendMessageBatchRequestEntry {
+            id: self.next_id.to_string(),
+            message_body: body,
+            ..Default::default()
+        });
+        self.next_id += 1;
+
+        if self.entries.len() == MAX_BATCH_LEN {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends any pending entries, resending the ones SQS fails through no fault of the sender.
+    /// Entries that fail for good are reported on stderr by their position in the input, counting
+    /// from 0.
+    fn flush(&mut self) -> Result<(), BoxError> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut req = SendMessageBatchRequest {
+            queue_url: self.queue_url.to_owned(),
+            entries: std::mem::replace(&mut self.entries, Vec::with_capacity(MAX_BATCH_LEN)),
+        };
+        self.bytes = 0;
+
+        // The entries SQS failed through no fault of the sender on the last attempt.
+        let mut unsent = Vec::new();
+        let retry = self.retry.clone();
+        let result = retry.run(|| {
+            let rsp = (self.send)(&req).map_err(FlushError::Request)?;
+            self.sent += rsp.successful.len();
+            let (retryable, rejected): (Vec<_>, Vec<_>) = rsp.failed.into_iter().partition(|entry| !entry.sender_fault);
+            for entry in rejected {
+                self.report(entry);
+            }
+            req.entries.retain(|entry| retryable.iter().any(|failed| failed.id == entry.id));
+            unsent = retryable;
+            if unsent.is_empty() { Ok(()) } else { Err(FlushError::Unsent) }
+        });
+
+        match result {
+            Err(FlushError::Request(err)) => Err(err.into()),
+            _ => {
+                for entry in unsent {
+                    self.report(entry);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn report(&mut self, entry: BatchResultErrorEntry) {
+        eprintln!("message {} failed: {}: {}", entry.id, entry.code, entry.message.unwrap_or_default());
+        self.failed += 1;
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
-}
\ No newline at end of file
+    run().err().map(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    });
+}
+
+fn load<R, F>(input: R, json: bool, batcher: &mut Batcher<F>) -> Result<(), BoxError>
+    where R: BufRead, F: FnMut(&SendMessageBatchRequest) -> Result<SendMessageBatchResult, SendMessageBatchError> {
+    if json {
+        let messages: Vec<serde_json::Value> = serde_json::from_reader(input)?;
+        for message in messages {
+            match message {
+                serde_json::Value::String(s) => batcher.push(s)?,
+                other => batcher.push(other.to_string())?,
+            }
+        }
+    } else {
+        for line in input.lines() {
+            let line = line?;
+            if !line.is_empty() {
+                batcher.push(line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run() -> Result<(), BoxError> {
+    let args: Args = docopt::Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())?;
+
+    let config = ClientConfig {
+        region: client::region(args.flag_region.as_ref().map(String::as_str),
+                               args.flag_endpoint.as_ref().map(String::as_str))?,
+        profile: None,
+        role_arn: None,
+    };
+    let client = config.sqs_client()?;
+    let retry = RetryPolicy {
+        max_retries: args.flag_max_retries,
+        base_delay: Duration::from_millis(args.flag_retry_delay),
+    };
+    let mut batcher = Batcher::new(|req: &SendMessageBatchRequest| client.send_message_batch(req).sync(),
+                                   args.arg_queue_url.as_str(),
+                                   retry);
+
+    if args.arg_file.is_empty() {
+        let stdin = std::io::stdin();
+        load(stdin.lock(), args.flag_json, &mut batcher)?;
+    } else {
+        for path in &args.arg_file {
+            let file = File::open(path)?;
+            load(BufReader::new(file), args.flag_json, &mut batcher)?;
+        }
+    }
+    batcher.flush()?;
+
+    println!("sent {} messages, {} failed", batcher.sent, batcher.failed);
+    if batcher.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_sqs::SendMessageBatchResultEntry;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { max_retries: 2, base_delay: Duration::from_millis(0) }
+    }
+
+    fn succeeded(id: &str) -> SendMessageBatchResultEntry {
+        SendMessageBatchResultEntry { id: id.to_owned(), message_id: format!("m{}", id) }
+    }
+
+    fn failed(id: &str, code: &str, sender_fault: bool) -> BatchResultErrorEntry {
+        BatchResultErrorEntry { id: id.to_owned(), code: code.to_owned(), message: None, sender_fault }
+    }
+
+    fn ids(req: &SendMessageBatchRequest) -> Vec<String> {
+        req.entries.iter().map(|entry| entry.id.clone()).collect()
+    }
+
+    #[test]
+    fn batches_by_count_and_size() {
+        let mut batches = Vec::new();
+        {
+            let mut batcher = Batcher::new(|req: &SendMessageBatchRequest| {
+                batches.push(ids(req));
+                Ok(SendMessageBatchResult {
+                    successful: req.entries.iter().map(|entry| succeeded(&entry.id)).collect(),
+                    failed: Vec::new(),
+                })
+            }, "queue", policy());
+            for i in 0..12 {
+                batcher.push(i.to_string()).unwrap();
+            }
+            // Two messages that don't fit in one batch together.
+            batcher.push("x".repeat(200 * 1024)).unwrap();
+            batcher.push("y".repeat(100 * 1024)).unwrap();
+            batcher.flush().unwrap();
+            assert_eq!((batcher.sent, batcher.failed), (14, 0));
+        }
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0].len(), 10);
+        assert_eq!(batches[1], vec!["10", "11", "12"]);
+        assert_eq!(batches[2], vec!["13"]);
+    }
+
+    #[test]
+    fn skips_oversized_messages() {
+        let mut batches = Vec::new();
+        {
+            let mut batcher = Batcher::new(|req: &SendMessageBatchRequest| {
+                batches.push(ids(req));
+                Ok(SendMessageBatchResult {
+                    successful: req.entries.iter().map(|entry| succeeded(&entry.id)).collect(),
+                    failed: Vec::new(),
+                })
+            }, "queue", policy());
+            batcher.push("a".to_owned()).unwrap();
+            batcher.push("b".repeat(MAX_MESSAGE_BYTES + 1)).unwrap();
+            batcher.push("c".to_owned()).unwrap();
+            batcher.flush().unwrap();
+            assert_eq!((batcher.sent, batcher.failed), (2, 1));
+        }
+        assert_eq!(batches, vec![vec!["0", "2"]]);
+    }
+
+    #[test]
+    fn resends_entries_failed_through_no_fault_of_the_sender() {
+        let mut batches = Vec::new();
+        {
+            let mut batcher = Batcher::new(|req: &SendMessageBatchRequest| {
+                batches.push(ids(req));
+                if batches.len() == 1 {
+                    return Err(SendMessageBatchError::Unknown("<ErrorResponse><Error><Code>ServiceUnavailable</Code>\
+                                                               </Error></ErrorResponse>".to_owned()));
+                }
+                Ok(match ids(req).len() {
+                    3 => SendMessageBatchResult {
+                        successful: vec![succeeded("0")],
+                        failed: vec![failed("1", "InternalError", false), failed("2", "InvalidMessageContents", true)],
+                    },
+                    _ => SendMessageBatchResult { successful: vec![succeeded("1")], failed: Vec::new() },
+                })
+            }, "queue", policy());
+            for body in &["a", "b", "c"] {
+                batcher.push(body.to_string()).unwrap();
+            }
+            batcher.flush().unwrap();
+            assert_eq!((batcher.sent, batcher.failed), (2, 1));
+        }
+        assert_eq!(batches, vec![vec!["0", "1", "2"], vec!["0", "1", "2"], vec!["1"]]);
+    }
+
+    #[test]
+    fn reports_entries_that_keep_failing() {
+        let mut calls = 0;
+        {
+            let mut batcher = Batcher::new(|_: &SendMessageBatchRequest| {
+                calls += 1;
+                Ok(SendMessageBatchResult { successful: Vec::new(), failed: vec![failed("0", "InternalError", false)] })
+            }, "queue", policy());
+            batcher.push("a".to_owned()).unwrap();
+            batcher.flush().unwrap();
+            assert_eq!((batcher.sent, batcher.failed), (0, 1));
+        }
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn fails_on_a_request_error_that_cant_succeed_on_retry() {
+        let mut calls = 0;
+        {
+            let mut batcher = Batcher::new(|_: &SendMessageBatchRequest| {
+                calls += 1;
+                Err(SendMessageBatchError::Unknown("<ErrorResponse><Error><Code>AccessDenied</Code>\
+                                                    </Error></ErrorResponse>".to_owned()))
+            }, "queue", policy());
+            batcher.push("a".to_owned()).unwrap();
+            assert!(batcher.flush().is_err());
+        }
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,93 @@
+use futures::Future;
+use rusoto_core::Region;
+use rusoto_core::credential::{AutoRefreshingProvider, AwsCredentials, CredentialsError,
+                              DefaultCredentialsProvider, ProfileProvider, ProvideAwsCredentials};
+use rusoto_core::request::HttpClient;
+use rusoto_s3::S3Client;
+use rusoto_sqs::SqsClient;
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use std::str::FromStr;
+
+use BoxError;
+
+/// Session name recorded in CloudTrail for roles assumed with `role_arn`.
+const SESSION_NAME: &'static str = "aws-tools";
+
+/// Returns the region called `name`, falling back to $AWS_DEFAULT_REGION and then us-east-1.
+/// If `endpoint` is given, requests go to that URL instead of AWS and are signed for the region,
+/// which is how S3-compatible stores like MinIO, Ceph RGW or LocalStack are reached.
+pub fn region(name: Option<&str>, endpoint: Option<&str>) -> Result<Region, BoxError> {
+    let default_region = std::env::var("AWS_DEFAULT_REGION")
+        .unwrap_or("us-east-1".to_owned());
+    let name = name.unwrap_or(default_region.as_str());
+    match endpoint {
+        Some(endpoint) => Ok(Region::Custom { name: name.to_owned(), endpoint: endpoint.to_owned() }),
+        None => Ok(Region::from_str(name)?),
+    }
+}
+
+/// Where and as whom to connect to AWS.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub region: Region,
+    /// Profile from the shared credentials file. The default credential chain is used if unset.
+    pub profile: Option<String>,
+    /// Role to assume, using the profile or default credentials to call AssumeRole.
+    pub role_arn: Option<String>,
+}
+
+impl ClientConfig {
+    /// Builds an S3 client. The rusoto S3 client always puts the bucket in the path, as in
+    /// `https://endpoint/bucket/key`, which S3-compatible stores expect.
+    pub fn s3_client(&self) -> Result<S3Client, BoxError> {
+        Ok(S3Client::new(HttpClient::new()?, self.credentials()?, self.region.clone()))
+    }
+
+    pub fn sqs_client(&self) -> Result<SqsClient, BoxError> {
+        Ok(SqsClient::new(HttpClient::new()?, self.credentials()?, self.region.clone()))
+    }
+
+    fn credentials(&self) -> Result<Credentials, BoxError> {
+        let base = match self.profile {
+            Some(ref profile) => {
+                let mut provider = ProfileProvider::new()?;
+                provider.set_profile(profile.as_str());
+                Credentials::Profile(provider)
+            }
+            None => Credentials::Default(DefaultCredentialsProvider::new()?),
+        };
+
+        match self.role_arn {
+            Some(ref role_arn) => {
+                // A custom endpoint is for the service being called, not STS, so roles are
+                // always assumed with AWS, in the region of that name.
+                let sts_region = Region::from_str(self.region.name())?;
+                let sts = StsClient::new(HttpClient::new()?, base, sts_region);
+                let assumed = StsAssumeRoleSessionCredentialsProvider::new(
+                    sts, role_arn.clone(), SESSION_NAME.to_owned(), None, None, None, None);
+                Ok(Credentials::AssumedRole(AutoRefreshingProvider::new(assumed)?))
+            }
+            None => Ok(base),
+        }
+    }
+}
+
+/// Credentials from whichever source a `ClientConfig` selects, so every client type can be
+/// built the same way.
+enum Credentials {
+    Default(DefaultCredentialsProvider),
+    Profile(ProfileProvider),
+    AssumedRole(AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>),
+}
+
+impl ProvideAwsCredentials for Credentials {
+    type Future = Box<dyn Future<Item = AwsCredentials, Error = CredentialsError> + Send>;
+
+    fn credentials(&self) -> Self::Future {
+        match *self {
+            Credentials::Default(ref p) => Box::new(p.credentials()),
+            Credentials::Profile(ref p) => Box::new(p.credentials()),
+            Credentials::AssumedRole(ref p) => Box::new(p.credentials()),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use regex::{self, Regex};
+use std::time::SystemTime;
+
+use BoxError;
+use listing::Listed;
+use timestamp;
+
+/// Selects src objects by size, age, storage class and key. Every condition that's set has to
+/// hold for an object to be selected.
+#[derive(Debug, Default)]
+pub struct ObjectFilter {
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    /// Seconds since the Unix epoch. Objects must have been modified before this.
+    pub modified_before: Option<i64>,
+    /// Seconds since the Unix epoch. Objects must have been modified at or after this.
+    pub modified_after: Option<i64>,
+    /// If not empty, objects must be in one of these storage classes.
+    pub storage_classes: Vec<String>,
+    /// If not empty, keys must match one of these.
+    pub include: Vec<Regex>,
+    /// Keys must match none of these.
+    pub exclude: Vec<Regex>,
+}
+
+impl ObjectFilter {
+    /// True if `obj` is selected. `key` is the part of its key that globs are matched against.
+    /// Objects whose modification time or storage class isn't known fail any condition on it.
+    pub fn matches(&self, obj: &Listed, key: &str) -> bool {
+        self.min_size.map_or(true, |min| obj.size >= min)
+            && self.max_size.map_or(true, |max| obj.size <= max)
+            && self.modified_before.map_or(true, |t| obj.last_modified.map_or(false, |m| m < t))
+            && self.modified_after.map_or(true, |t| obj.last_modified.map_or(false, |m| m >= t))
+            && (self.storage_classes.is_empty()
+                || obj.storage_class.as_ref().map_or(false, |class| self.storage_classes.contains(class)))
+            && (self.include.is_empty() || self.include.iter().any(|re| re.is_match(key)))
+            && !self.exclude.iter().any(|re| re.is_match(key))
+    }
+
+    /// True if any condition needs more of an object than its key.
+    pub fn needs_object(&self) -> bool {
+        self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.modified_before.is_some()
+            || self.modified_after.is_some()
+            || !self.storage_classes.is_empty()
+    }
+}
+
+/// Parses a size in bytes, optionally followed by K, M, G or T for powers of 1024.
+pub fn parse_size(s: &str) -> Result<i64, BoxError> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let multiplier: i64 = match unit.to_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size {}", s).into()),
+    };
+    digits.parse::<i64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {}", s).into())
+}
+
+/// Parses a UTC date like `2018-06-01`, a UTC time like `2018-06-01T12:00:00Z`, or an age like
+/// `90d` or `12h`, into seconds since the Unix epoch.
+pub fn parse_time(s: &str) -> Result<i64, BoxError> {
+    let invalid = || format!("invalid time {}: expected a date, a time or an age like 90d", s);
+    let age_unit = match s.chars().last() {
+        Some('d') => Some(86_400),
+        Some('h') => Some(3_600),
+        _ => None,
+    };
+    if let Some(unit) = age_unit {
+        let n = s[..s.len() - 1].parse::<i64>().map_err(|_| invalid())?;
+        let now = timestamp::from_system_time(SystemTime::now()).ok_or("system clock is before 1970")?;
+        return Ok(now - n * unit);
+    }
+
+    let time = if s.contains('T') { timestamp::parse(s) } else { timestamp::parse(&format!("{}T00:00:00Z", s)) };
+    time.ok_or_else(|| invalid().into())
+}
+
+/// Compiles a glob into a regex matching whole keys. `*` and `?` don't match `/`, but `**`
+/// does, and `**/` also matches nothing, so `**/a.log` matches `a.log` at the top level too.
+/// `[...]` matches a character class, negated with a leading `!`.
+pub fn glob(pattern: &str) -> Result<Regex, BoxError> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        ']' => {
+                            closed = true;
+                            break;
+                        }
+                        '-' => re.push('-'),
+                        c => re.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                if !closed {
+                    return Err(format!("invalid glob {}: unclosed [", pattern).into());
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).map_err(|err| format!("invalid glob {}: {}", pattern, err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size("5G").unwrap(), 5 << 30);
+        assert_eq!(parse_size("2T").unwrap(), 2 << 40);
+        for size in &["", "K", "1.5M", "-1", "10KB", "99999999999T"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("2018-06-01").unwrap(), 1_527_811_200);
+        assert_eq!(parse_time("2018-06-01T12:30:00Z").unwrap(), 1_527_856_200);
+        assert_eq!(parse_time("2020-02-29").unwrap(), 1_582_934_400);
+        let now = timestamp::from_system_time(SystemTime::now()).unwrap();
+        let age = now - parse_time("90d").unwrap();
+        assert!(age >= 90 * 86_400 && age < 90 * 86_400 + 60, "{}", age);
+        let age = now - parse_time("12h").unwrap();
+        assert!(age >= 12 * 3_600 && age < 12 * 3_600 + 60, "{}", age);
+        for time in &["", "d", "1.5d", "yesterday", "2018-13-01", "2018-02-31", "2018-04-31", "2019-02-29",
+                     "1900-02-29", "2018-06-01T25:00:00Z"] {
+            assert!(parse_time(time).is_err(), "{}", time);
+        }
+    }
+
+    #[test]
+    fn compiles_globs() {
+        let re = glob("*.log").unwrap();
+        assert!(re.is_match("app.log"));
+        assert!(!re.is_match("2018/app.log"));
+        assert!(!re.is_match("app.log.gz"));
+
+        let re = glob("**/app-?.log").unwrap();
+        assert!(re.is_match("a/b/app-1.log"));
+        assert!(re.is_match("app-1.log"));
+        assert!(!re.is_match("a/app-10.log"));
+        assert!(!re.is_match("aapp-1.log"));
+        assert!(glob("logs/**").unwrap().is_match("logs/a/b.log"));
+
+        let re = glob("[a-c]*/[!.]*").unwrap();
+        assert!(re.is_match("b/x"));
+        assert!(!re.is_match("d/x"));
+        assert!(!re.is_match("b/.x"));
+
+        assert!(glob("a+b(c)").unwrap().is_match("a+b(c)"));
+        assert!(glob("[abc").is_err());
+    }
+
+    #[test]
+    fn matches_every_condition() {
+        let filter = ObjectFilter {
+            min_size: Some(10),
+            modified_before: Some(1_000),
+            storage_classes: vec!["STANDARD".to_owned()],
+            exclude: vec![glob("*.tmp").unwrap()],
+            ..Default::default()
+        };
+        let obj = Listed {
+            key: "a.log".to_owned(),
+            size: 10,
+            etag: None,
+            last_modified: Some(999),
+            version_id: None,
+            storage_class: Some("STANDARD".to_owned()),
+        };
+        assert!(filter.matches(&obj, "a.log"));
+        assert!(!filter.matches(&obj, "a.tmp"));
+        assert!(!filter.matches(&Listed { size: 9, ..obj.clone() }, "a.log"));
+        assert!(!filter.matches(&Listed { last_modified: None, ..obj.clone() }, "a.log"));
+        assert!(!filter.matches(&Listed { storage_class: Some("GLACIER".to_owned()), ..obj.clone() }, "a.log"));
+        assert!(filter.needs_object());
+        assert!(!ObjectFilter { exclude: vec![glob("*.tmp").unwrap()], ..Default::default() }.needs_object());
+    }
+}
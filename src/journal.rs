@@ -0,0 +1,219 @@
+use serde_json;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use BoxError;
+
+/// One line of the journal. Keys can contain any character, including newlines, so each record
+/// is written as a line of JSON rather than as delimited text. `version_id` is only written for
+/// moves of specific object versions.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Copied {
+        src_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version_id: Option<String>,
+        dest_key: String,
+    },
+    Deleted {
+        src_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version_id: Option<String>,
+    },
+}
+
+/// Progress of each src object, keyed by src key and version ID.
+type ProgressMap = HashMap<(String, Option<String>), Progress>;
+
+#[derive(Debug)]
+enum Progress {
+    Copied(String),
+    Deleted,
+}
+
+/// Append-only log of completed copies and deletes, used to resume an interrupted move.
+///
+/// Each record is written with a single `write_all` as soon as the step completes, so at worst a
+/// crash loses the last, partially written line. That line is dropped when the journal is read
+/// back, and the step it described is simply redone.
+pub struct Journal {
+    /// None if the journal was opened read-only.
+    file: Option<Mutex<File>>,
+    progress: ProgressMap,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it doesn't exist, and loads existing records.
+    /// A partial last line is cut off, so new records start on a line of their own.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Journal, BoxError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let (progress, complete_len) = load(&file, path)?;
+        if file.metadata()?.len() > complete_len {
+            file.set_len(complete_len)?;
+        }
+        Ok(Journal { file: Some(Mutex::new(file)), progress })
+    }
+
+    /// Loads the journal at `path` without creating or changing it, for dry runs. A missing
+    /// journal is empty, and nothing can be recorded.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Journal, BoxError> {
+        let path = path.as_ref();
+        let progress = match File::open(path) {
+            Ok(file) => load(&file, path)?.0,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Journal { file: None, progress })
+    }
+
+    /// True if the journal shows `src_key`, or the given version of it, was both copied and
+    /// deleted.
+    pub fn is_moved(&self, src_key: &str, version_id: Option<&str>) -> bool {
+        match self.get(src_key, version_id) {
+            Some(&Progress::Deleted) => true,
+            _ => false,
+        }
+    }
+
+    /// True if the journal shows `src_key`, or the given version of it, was already copied to
+    /// `dest_key`.
+    pub fn is_copied(&self, src_key: &str, version_id: Option<&str>, dest_key: &str) -> bool {
+        match self.get(src_key, version_id) {
+            Some(&Progress::Copied(ref copied_to)) => copied_to == dest_key,
+            Some(&Progress::Deleted) => true,
+            None => false,
+        }
+    }
+
+    /// The dest key the journal shows `src_key`, or the given version of it, was copied to.
+    pub fn copied_to(&self, src_key: &str, version_id: Option<&str>) -> Option<&str> {
+        match self.get(src_key, version_id) {
+            Some(&Progress::Copied(ref copied_to)) => Some(copied_to),
+            _ => None,
+        }
+    }
+
+    pub fn record_copy(&self, src_key: &str, version_id: Option<&str>, dest_key: &str) -> Result<(), BoxError> {
+        self.append(&Record::Copied {
+            src_key: src_key.to_owned(),
+            version_id: version_id.map(str::to_owned),
+            dest_key: dest_key.to_owned(),
+        })
+    }
+
+    pub fn record_delete(&self, src_key: &str, version_id: Option<&str>) -> Result<(), BoxError> {
+        self.append(&Record::Deleted { src_key: src_key.to_owned(), version_id: version_id.map(str::to_owned) })
+    }
+
+    fn get(&self, src_key: &str, version_id: Option<&str>) -> Option<&Progress> {
+        self.progress.get(&(src_key.to_owned(), version_id.map(str::to_owned)))
+    }
+
+    fn append(&self, record: &Record) -> Result<(), BoxError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let file = self.file.as_ref().ok_or("can't record progress in a read-only journal")?;
+        file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Reads the complete records in a journal file. Returns the progress they show and the length
+/// of the file up to the end of the last complete line.
+fn load(file: &File, path: &Path) -> Result<(ProgressMap, u64), BoxError> {
+    let mut progress = HashMap::new();
+    // Lines are read as bytes, since a crash can cut one off in the middle of a character.
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    let mut complete_len = 0;
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        let record = serde_json::from_slice(&line[..n - 1])
+            .map_err(|err| format!("corrupt journal {}: {}", path.display(), err))?;
+        match record {
+            Record::Copied { src_key, version_id, dest_key } => {
+                progress.insert((src_key, version_id), Progress::Copied(dest_key));
+            }
+            Record::Deleted { src_key, version_id } => {
+                progress.insert((src_key, version_id), Progress::Deleted);
+            }
+        }
+        complete_len += n as u64;
+    }
+    Ok((progress, complete_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("aws-tools-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn resumes_after_partial_line() {
+        let path = temp_path("journal-partial");
+        let mut contents = b"{\"op\":\"copied\",\"src_key\":\"a\",\"dest_key\":\"b\"}\n".to_vec();
+        // Cut off in the middle of the two-byte encoding of the é.
+        contents.extend_from_slice(&"{\"op\":\"deleted\",\"src_key\":\"é\"}\n".as_bytes()[..28]);
+        fs::write(&path, &contents).unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert!(journal.is_copied("a", None, "b"));
+        assert!(!journal.is_moved("é", None));
+        journal.record_delete("a", None).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path).unwrap();
+        assert!(journal.is_moved("a", None));
+        journal.record_copy("c", Some("v1"), "d").unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path).unwrap();
+        assert!(journal.is_moved("a", None));
+        assert_eq!(journal.copied_to("c", Some("v1")), Some("d"));
+        assert_eq!(journal.copied_to("c", None), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_without_changing_the_file() {
+        let path = temp_path("journal-read-only");
+        let journal = Journal::open_read_only(&path).unwrap();
+        assert!(!journal.is_moved("a", None));
+        assert!(journal.record_delete("a", None).is_err());
+        assert!(!path.exists());
+
+        let contents = b"{\"op\":\"deleted\",\"src_key\":\"a\"}\n{\"op\":\"cop";
+        fs::write(&path, &contents[..]).unwrap();
+        let journal = Journal::open_read_only(&path).unwrap();
+        assert!(journal.is_moved("a", None));
+        drop(journal);
+        assert_eq!(fs::read(&path).unwrap(), contents.to_vec());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupt_line() {
+        let path = temp_path("journal-corrupt");
+        fs::write(&path, b"not json\n{\"op\":\"deleted\",\"src_key\":\"a\"}\n").unwrap();
+        let err = Journal::open(&path).err().unwrap();
+        assert!(err.to_string().starts_with("corrupt journal"), "{}", err);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+extern crate bytes;
+extern crate csv;
+extern crate flate2;
+extern crate futures;
+#[macro_use]
+extern crate lazy_static;
+extern crate md5;
+extern crate parquet;
+extern crate rand;
+extern crate regex;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+extern crate rusoto_sqs;
+extern crate rusoto_sts;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+pub mod client;
+pub mod filter;
+pub mod journal;
+pub mod listing;
+pub mod manifest;
+pub mod output;
+pub mod pool;
+pub mod retry;
+pub mod rewrite;
+pub mod s3url;
+pub mod template;
+pub mod timestamp;
+pub mod transfer;
+
+/// Error type for work that may run on a worker thread.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
@@ -0,0 +1,144 @@
+use rusoto_s3::{self, S3};
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+use BoxError;
+use retry::RetryPolicy;
+use timestamp;
+
+/// An object found by listing the src.
+#[derive(Debug, Clone)]
+pub struct Listed {
+    pub key: String,
+    pub size: i64,
+    pub etag: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub last_modified: Option<i64>,
+    /// Only set when listing object versions.
+    pub version_id: Option<String>,
+    /// Not set for local files.
+    pub storage_class: Option<String>,
+}
+
+/// Lists `bucket` under `prefix` with ListObjectsV2, calling `f` for each object in key order
+/// until it returns false.
+pub fn list_s3<S, F>(client: &S, retry: &RetryPolicy, bucket: &str, prefix: Option<&str>,
+                     start_after: Option<&str>, mut f: F) -> Result<(), BoxError>
+    where S: S3, F: FnMut(Listed) -> Result<bool, BoxError> {
+    let mut list_req = rusoto_s3::ListObjectsV2Request::default();
+    list_req.bucket = bucket.to_owned();
+    list_req.prefix = prefix.map(str::to_owned);
+    list_req.start_after = start_after.map(str::to_owned);
+
+    loop {
+        let rsp = retry.run(|| client.list_objects_v2(&list_req).sync())?;
+        for obj in rsp.contents.unwrap_or_default() {
+            if let (Some(key), Some(size)) = (obj.key, obj.size) {
+                let last_modified = obj.last_modified.as_ref().and_then(|t| timestamp::parse(t));
+                let storage_class = obj.storage_class;
+                if !f(Listed { key, size, etag: obj.e_tag, last_modified, version_id: None, storage_class })? {
+                    return Ok(());
+                }
+            }
+        }
+
+        match rsp.next_continuation_token {
+            Some(token) if rsp.is_truncated.unwrap_or(false) => list_req.continuation_token = Some(token),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Lists every version of the objects in `bucket` under `prefix` with ListObjectVersions,
+/// calling `f` with the versions of each key in turn, oldest first, until it returns false.
+/// Delete markers are left out.
+pub fn list_s3_versions<S, F>(client: &S, retry: &RetryPolicy, bucket: &str, prefix: Option<&str>,
+                              start_after: Option<&str>, mut f: F) -> Result<(), BoxError>
+    where S: S3, F: FnMut(Vec<Listed>) -> Result<bool, BoxError> {
+    let mut list_req = rusoto_s3::ListObjectVersionsRequest::default();
+    list_req.bucket = bucket.to_owned();
+    list_req.prefix = prefix.map(str::to_owned);
+    list_req.key_marker = start_after.map(str::to_owned);
+
+    // S3 lists the versions of a key newest first, possibly split across pages, so they're
+    // collected until the next key comes up.
+    let mut versions: Vec<Listed> = Vec::new();
+    loop {
+        let rsp = retry.run(|| client.list_object_versions(&list_req).sync())?;
+        for version in rsp.versions.unwrap_or_default() {
+            if let (Some(key), Some(size)) = (version.key, version.size) {
+                if versions.last().map_or(false, |last| last.key != key) {
+                    versions.reverse();
+                    if !f(mem::replace(&mut versions, Vec::new()))? {
+                        return Ok(());
+                    }
+                }
+                versions.push(Listed {
+                    key,
+                    size,
+                    etag: version.e_tag,
+                    last_modified: version.last_modified.as_ref().and_then(|t| timestamp::parse(t)),
+                    version_id: version.version_id,
+                    storage_class: version.storage_class,
+                });
+            }
+        }
+
+        if !rsp.is_truncated.unwrap_or(false) {
+            break;
+        }
+        list_req.key_marker = rsp.next_key_marker;
+        list_req.version_id_marker = rsp.next_version_id_marker;
+    }
+
+    if !versions.is_empty() {
+        versions.reverse();
+        f(versions)?;
+    }
+    Ok(())
+}
+
+/// Lists the regular files under `root`, calling `f` for each in key order until it returns
+/// false. Keys are paths relative to `root` with `/` as the separator. Symlinks are skipped.
+pub fn list_local<F>(root: &Path, start_after: Option<&str>, mut f: F) -> Result<(), BoxError>
+    where F: FnMut(Listed) -> Result<bool, BoxError> {
+    let mut files = Vec::new();
+    walk(root, "", &mut files)?;
+    files.sort_by(|a, b| a.key.cmp(&b.key));
+
+    for file in files {
+        if start_after.map_or(false, |start_after| file.key.as_str() <= start_after) {
+            continue;
+        }
+        if !f(file)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn walk(dir: &Path, prefix: &str, files: &mut Vec<Listed>) -> Result<(), BoxError> {
+    for entry in fs::read_dir(dir).map_err(|err| format!("can't list {}: {}", dir.display(), err))? {
+        let entry = entry?;
+        let key = match entry.file_name().into_string() {
+            Ok(name) => format!("{}{}", prefix, name),
+            Err(_) => return Err(format!("{} isn't valid UTF-8", entry.path().display()).into()),
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), &format!("{}/", key), files)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            files.push(Listed {
+                key,
+                size: metadata.len() as i64,
+                etag: None,
+                last_modified: metadata.modified().ok().and_then(timestamp::from_system_time),
+                version_id: None,
+                storage_class: None,
+            });
+        }
+    }
+    Ok(())
+}
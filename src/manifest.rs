@@ -0,0 +1,421 @@
+use bytes::Bytes;
+use csv;
+use flate2::read::GzDecoder;
+use futures::{Future, Stream};
+use futures::stream::Wait;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use rusoto_s3::{self, S3};
+use serde_json;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+
+use BoxError;
+use listing::Listed;
+use retry::RetryPolicy;
+use s3url::{self, Location};
+use timestamp;
+
+/// A src object named by a manifest.
+pub enum Entry {
+    /// A key from a key list, or from an inventory without sizes, along with its bucket if the
+    /// manifest gives one. The object has to be looked up to find its size.
+    Key { bucket: Option<String>, key: String },
+    /// The current version of an object in an S3 Inventory report.
+    Object { bucket: String, object: Listed },
+}
+
+/// The S3 Inventory `manifest.json` that's written alongside each report.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InventoryManifest {
+    /// ARN of the bucket the data files are in.
+    destination_bucket: String,
+    file_format: String,
+    /// Comma-separated column names of CSV data files.
+    file_schema: String,
+    files: Vec<InventoryFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InventoryFile {
+    key: String,
+}
+
+/// The columns of an inventory report that are used here. Which ones a report has depends on
+/// how the inventory is configured.
+#[derive(Debug, Default)]
+struct InventoryRow {
+    bucket: Option<String>,
+    key: Option<String>,
+    is_latest: Option<bool>,
+    is_delete_marker: Option<bool>,
+    size: Option<i64>,
+    last_modified: Option<i64>,
+    etag: Option<String>,
+    storage_class: Option<String>,
+}
+
+/// Reads the src objects named by `manifest`, calling `f` for each until it returns false.
+///
+/// A file named `manifest.json` is read as an S3 Inventory manifest, and its CSV or Parquet data
+/// files are read in turn, one at a time. ORC inventories aren't supported. A file ending in
+/// `.csv` holds bucket and URL-encoded key columns, as S3 Batch Operations takes them, and any
+/// other file holds one key per line. Key lists ending in `.gz` are decompressed. Files in S3 are
+/// read with `client`, and streamed rather than held in memory, except for Parquet data files.
+pub fn read<S, F>(client: &S, retry: &RetryPolicy, manifest: &Location, f: F) -> Result<(), BoxError>
+    where S: S3, F: FnMut(Entry) -> Result<bool, BoxError> {
+    let (name, reader): (String, Box<dyn Read>) = match *manifest {
+        Location::S3(ref url) => {
+            let key = url.prefix.as_ref().ok_or("--manifest must name an object, not a bucket")?;
+            (key.clone(), Box::new(open(client, retry, &url.bucket, key)?))
+        }
+        Location::Local(ref path) => {
+            let file = File::open(path).map_err(|err| format!("can't open {}: {}", path.display(), err))?;
+            (path.to_string_lossy().into_owned(), Box::new(file))
+        }
+    };
+
+    if name.ends_with("manifest.json") {
+        let inventory = serde_json::from_reader(reader)
+            .map_err(|err| format!("{} isn't an S3 Inventory manifest: {}", name, err))?;
+        return read_inventory(client, retry, &inventory, f);
+    }
+    read_keys(&name, reader, f)
+}
+
+/// Reads the key list or `.csv` file called `name`.
+fn read_keys<F>(name: &str, reader: Box<dyn Read>, mut f: F) -> Result<(), BoxError>
+    where F: FnMut(Entry) -> Result<bool, BoxError> {
+    let reader: Box<dyn Read> = if name.ends_with(".gz") { Box::new(GzDecoder::new(reader)) } else { reader };
+    if name.trim_end_matches(".gz").ends_with(".csv") {
+        let mut csv_reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(reader);
+        for record in csv_reader.records() {
+            let record = record?;
+            let (bucket, key) = match (record.get(0), record.get(1)) {
+                (Some(bucket), Some(key)) if !key.is_empty() => (bucket, s3url::percent_decode(key)),
+                _ => {
+                    let line = record.position().map_or(0, |pos| pos.line());
+                    return Err(format!("{} line {}: expected a bucket and key", name, line).into());
+                }
+            };
+            if !f(Entry::Key { bucket: Some(bucket.to_owned()), key })? {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        let key = line.trim_end_matches('\r');
+        if !key.is_empty() && !f(Entry::Key { bucket: None, key: key.to_owned() })? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_inventory<S, F>(client: &S, retry: &RetryPolicy, inventory: &InventoryManifest, mut f: F)
+                        -> Result<(), BoxError>
+    where S: S3, F: FnMut(Entry) -> Result<bool, BoxError> {
+    // The destination bucket is given as an ARN, like arn:aws:s3:::bucket.
+    let bucket = inventory.destination_bucket.rsplit(':').next().unwrap_or("");
+    let columns: Vec<String> = inventory.file_schema.split(',').map(|name| normalize(name.trim())).collect();
+    let parquet = match inventory.file_format.as_str() {
+        "CSV" => false,
+        "Parquet" => true,
+        other => return Err(format!("{} inventories aren't supported; use CSV or Parquet", other).into()),
+    };
+
+    // Passes a row on to `f`, returning false once it's had enough.
+    let mut emit = |row: InventoryRow| -> Result<bool, BoxError> {
+        match row.into_entry()? {
+            Some(entry) => f(entry),
+            None => Ok(true),
+        }
+    };
+
+    for file in &inventory.files {
+        if parquet {
+            // Parquet metadata is at the end of the file, so it can't be streamed.
+            let reader = SerializedFileReader::new(Bytes::from(get(client, retry, bucket, &file.key)?))?;
+            for record in reader.get_row_iter(None)? {
+                let mut row = InventoryRow::default();
+                for (name, value) in record?.get_column_iter() {
+                    row.set_parquet(&normalize(name), value);
+                }
+                if !emit(row)? {
+                    return Ok(());
+                }
+            }
+        } else {
+            let mut csv_reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(GzDecoder::new(open(client, retry, bucket, &file.key)?));
+            for record in csv_reader.records() {
+                let mut row = InventoryRow::default();
+                for (name, value) in columns.iter().zip(record?.iter()) {
+                    row.set_csv(name, value)?;
+                }
+                if !emit(row)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// CSV reports name columns like `LastModifiedDate`, and Parquet reports like
+/// `last_modified_date`, so both are compared without case or underscores.
+fn normalize(column: &str) -> String {
+    column.chars().filter(|&c| c != '_').flat_map(char::to_lowercase).collect()
+}
+
+impl InventoryRow {
+    /// Sets a column from a CSV report, where keys are URL-encoded and every value is text.
+    fn set_csv(&mut self, column: &str, value: &str) -> Result<(), BoxError> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        let invalid = || format!("invalid {} in inventory: {}", column, value);
+        match column {
+            "bucket" => self.bucket = Some(value.to_owned()),
+            "key" => self.key = Some(s3url::percent_decode(value)),
+            "islatest" => self.is_latest = Some(value.parse().map_err(|_| invalid())?),
+            "isdeletemarker" => self.is_delete_marker = Some(value.parse().map_err(|_| invalid())?),
+            "size" => self.size = Some(value.parse().map_err(|_| invalid())?),
+            "lastmodifieddate" => self.last_modified = Some(timestamp::parse(value).ok_or_else(invalid)?),
+            "etag" => self.etag = Some(value.to_owned()),
+            "storageclass" => self.storage_class = Some(value.to_owned()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn set_parquet(&mut self, column: &str, value: &Field) {
+        match (column, value) {
+            ("bucket", &Field::Str(ref s)) => self.bucket = Some(s.clone()),
+            ("key", &Field::Str(ref s)) => self.key = Some(s.clone()),
+            ("islatest", &Field::Bool(b)) => self.is_latest = Some(b),
+            ("isdeletemarker", &Field::Bool(b)) => self.is_delete_marker = Some(b),
+            ("size", &Field::Long(n)) => self.size = Some(n),
+            ("lastmodifieddate", &Field::TimestampMillis(ms)) => self.last_modified = Some(ms / 1000),
+            ("etag", &Field::Str(ref s)) => self.etag = Some(s.clone()),
+            ("storageclass", &Field::Str(ref s)) => self.storage_class = Some(s.clone()),
+            _ => {}
+        }
+    }
+
+    /// Returns None for noncurrent versions and delete markers, which aren't moved.
+    fn into_entry(self) -> Result<Option<Entry>, BoxError> {
+        if self.is_latest == Some(false) || self.is_delete_marker == Some(true) {
+            return Ok(None);
+        }
+        let (bucket, key) = match (self.bucket, self.key) {
+            (Some(bucket), Some(key)) => (bucket, key),
+            _ => return Err("inventory row has no bucket or key".into()),
+        };
+        let size = match self.size {
+            Some(size) => size,
+            None => return Ok(Some(Entry::Key { bucket: Some(bucket), key })),
+        };
+        Ok(Some(Entry::Object {
+            bucket,
+            object: Listed {
+                key,
+                size,
+                // Inventories leave out the quotes that listings put around ETags.
+                etag: self.etag.map(|etag| format!("\"{}\"", etag)),
+                last_modified: self.last_modified,
+                version_id: None,
+                storage_class: self.storage_class,
+            },
+        }))
+    }
+}
+
+/// Reads a whole object. Reading the body is retried along with the request.
+fn get<S: S3>(client: &S, retry: &RetryPolicy, bucket: &str, key: &str) -> Result<Vec<u8>, BoxError> {
+    let get_req = rusoto_s3::GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    retry.run(|| -> Result<Vec<u8>, BoxError> {
+        let rsp = client.get_object(&get_req).sync()?;
+        let body = rsp.body.ok_or("GetObject returned no body")?;
+        Ok(body.concat2().wait()?)
+    }).map_err(|err| format!("can't read s3://{}/{}: {}", bucket, key, err).into())
+}
+
+/// Starts reading an object, returning its body as it arrives. Only the request is retried, so
+/// a connection dropped partway through fails the read.
+fn open<S: S3>(client: &S, retry: &RetryPolicy, bucket: &str, key: &str) -> Result<BodyReader, BoxError> {
+    let get_req = rusoto_s3::GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let url = format!("s3://{}/{}", bucket, key);
+    let body = retry.run(|| -> Result<rusoto_s3::StreamingBody, BoxError> {
+        let rsp = client.get_object(&get_req).sync()?;
+        Ok(rsp.body.ok_or("GetObject returned no body")?)
+    }).map_err(|err| format!("can't read {}: {}", url, err))?;
+    Ok(BodyReader { url, chunks: body.wait(), chunk: Cursor::new(Vec::new()) })
+}
+
+/// Reads a GetObject response body, blocking for each chunk.
+struct BodyReader {
+    /// Where the body comes from, for errors.
+    url: String,
+    chunks: Wait<rusoto_s3::StreamingBody>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.chunks.next() {
+                Some(Ok(chunk)) => self.chunk = Cursor::new(chunk),
+                Some(Err(err)) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("can't read {}: {}", self.url, err)));
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(name: &str, data: &str) -> Result<Vec<Entry>, BoxError> {
+        let mut entries = Vec::new();
+        read_keys(name, Box::new(Cursor::new(data.as_bytes().to_vec())), |entry| {
+            entries.push(entry);
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    fn key(entry: &Entry) -> (Option<&str>, &str) {
+        match *entry {
+            Entry::Key { ref bucket, ref key } => (bucket.as_ref().map(String::as_str), key.as_str()),
+            Entry::Object { .. } => panic!("expected a key without a size"),
+        }
+    }
+
+    #[test]
+    fn reads_bodies_across_chunks() {
+        let chunks = vec![b"a\nb".to_vec(), Vec::new(), b"c\nd".to_vec()];
+        let body = rusoto_s3::StreamingBody::new(::futures::stream::iter_ok(chunks));
+        let reader = BodyReader { url: "s3://src/keys.txt".to_owned(), chunks: body.wait(), chunk: Cursor::new(Vec::new()) };
+        let mut entries = Vec::new();
+        read_keys("keys.txt", Box::new(reader), |entry| {
+            entries.push(entry);
+            Ok(true)
+        }).unwrap();
+        let keys: Vec<_> = entries.iter().map(key).collect();
+        assert_eq!(keys, vec![(None, "a"), (None, "bc"), (None, "d")]);
+    }
+
+    #[test]
+    fn normalizes_column_names() {
+        assert_eq!(normalize("LastModifiedDate"), "lastmodifieddate");
+        assert_eq!(normalize("last_modified_date"), "lastmodifieddate");
+        assert_eq!(normalize("ETag"), normalize("e_tag"));
+    }
+
+    #[test]
+    fn reads_bucket_and_key_csv() {
+        let entries = read_all("keys.csv", "src,logs/a%20b.txt\r\nsrc,logs/c.txt,extra\n").unwrap();
+        let keys: Vec<_> = entries.iter().map(key).collect();
+        assert_eq!(keys, vec![(Some("src"), "logs/a b.txt"), (Some("src"), "logs/c.txt")]);
+
+        let err = read_all("keys.csv", "src,a.txt\nsrc\n").err().unwrap().to_string();
+        assert_eq!(err, "keys.csv line 2: expected a bucket and key");
+        assert!(read_all("keys.csv", "src,\n").is_err());
+    }
+
+    #[test]
+    fn reads_key_lists() {
+        let entries = read_all("keys.txt", "a,b.txt\r\n\nc%20d.txt").unwrap();
+        let keys: Vec<_> = entries.iter().map(key).collect();
+        // Only .csv keys are URL-encoded.
+        assert_eq!(keys, vec![(None, "a,b.txt"), (None, "c%20d.txt")]);
+    }
+
+    #[test]
+    fn reads_csv_inventory_rows() {
+        let mut row = InventoryRow::default();
+        for &(column, value) in &[("bucket", "src"), ("key", "logs/a%20b.txt"), ("size", "5"),
+                                  ("lastmodifieddate", "2018-06-01T12:30:00.000Z"), ("etag", "abc"),
+                                  ("storageclass", "STANDARD"), ("islatest", "true"), ("isdeletemarker", ""),
+                                  ("replicationstatus", "COMPLETED")] {
+            row.set_csv(column, value).unwrap();
+        }
+        match row.into_entry().unwrap() {
+            Some(Entry::Object { bucket, object }) => {
+                assert_eq!(bucket, "src");
+                assert_eq!(object.key, "logs/a b.txt");
+                assert_eq!(object.size, 5);
+                assert_eq!(object.etag, Some("\"abc\"".to_owned()));
+                assert_eq!(object.last_modified, Some(1_527_856_200));
+                assert_eq!(object.storage_class, Some("STANDARD".to_owned()));
+            }
+            _ => panic!("expected an object"),
+        }
+
+        assert!(InventoryRow::default().set_csv("size", "five").is_err());
+        assert!(InventoryRow::default().set_csv("islatest", "yes").is_err());
+        assert!(InventoryRow::default().set_csv("lastmodifieddate", "yesterday").is_err());
+    }
+
+    #[test]
+    fn reads_parquet_inventory_rows() {
+        let mut row = InventoryRow::default();
+        row.set_parquet("bucket", &Field::Str("src".to_owned()));
+        // Parquet keys aren't URL-encoded.
+        row.set_parquet("key", &Field::Str("logs/a%20b.txt".to_owned()));
+        row.set_parquet("size", &Field::Long(5));
+        row.set_parquet("lastmodifieddate", &Field::TimestampMillis(1_527_856_200_999));
+        row.set_parquet("etag", &Field::Str("abc".to_owned()));
+        // Values of an unexpected type are ignored.
+        row.set_parquet("islatest", &Field::Str("false".to_owned()));
+        match row.into_entry().unwrap() {
+            Some(Entry::Object { bucket, object }) => {
+                assert_eq!(bucket, "src");
+                assert_eq!(object.key, "logs/a%20b.txt");
+                assert_eq!(object.size, 5);
+                assert_eq!(object.etag, Some("\"abc\"".to_owned()));
+                assert_eq!(object.last_modified, Some(1_527_856_200));
+            }
+            _ => panic!("expected an object"),
+        }
+    }
+
+    #[test]
+    fn turns_inventory_rows_into_entries() {
+        let row = || InventoryRow {
+            bucket: Some("src".to_owned()),
+            key: Some("a.txt".to_owned()),
+            ..Default::default()
+        };
+        // Without a size, the object has to be looked up.
+        match row().into_entry().unwrap() {
+            Some(ref entry) => assert_eq!(key(entry), (Some("src"), "a.txt")),
+            None => panic!("expected a key"),
+        }
+        assert!(InventoryRow { is_latest: Some(false), ..row() }.into_entry().unwrap().is_none());
+        assert!(InventoryRow { is_delete_marker: Some(true), ..row() }.into_entry().unwrap().is_none());
+        assert!(InventoryRow { key: None, ..row() }.into_entry().is_err());
+    }
+}
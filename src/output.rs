@@ -0,0 +1,273 @@
+use serde_json;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use BoxError;
+use s3url::Location;
+
+/// How records are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Tab-separated src, dest and size, as URLs for planned moves and as keys otherwise. The
+    /// src is empty for deletes. Failures and skipped objects go to stderr.
+    Text,
+    /// A single JSON array of records.
+    Json,
+    /// One JSON record per line.
+    Jsonl,
+    /// CSV with a header row.
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Move,
+    /// Copy and leave the src in place.
+    Copy,
+    /// Delete a dest object that no src object maps to. The record has an empty `src_key`.
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Not attempted, because this is a dry run.
+    Planned,
+    Done,
+    /// Left alone because of a conflict at dest.
+    Skipped,
+    /// Left alone because dest is already up to date. Not shown in text output.
+    Unchanged,
+    Failed,
+}
+
+/// The outcome for a single object.
+#[derive(Debug, Serialize)]
+pub struct Record {
+    pub src_key: String,
+    pub version_id: Option<String>,
+    pub dest_key: String,
+    /// Not known for a key named by a manifest that failed before it could be looked up.
+    pub size: Option<i64>,
+    pub etag: Option<String>,
+    pub action: Action,
+    pub status: Status,
+    /// Why the object failed or was skipped.
+    pub error: Option<String>,
+}
+
+const CSV_HEADER: &'static str = "src_key,version_id,dest_key,size,etag,action,status,error";
+
+/// Prints records to stdout as they come in from any thread. `finish` must be called once all
+/// records are written, since JSON output isn't complete until then.
+pub struct Output {
+    format: Format,
+    src: Location,
+    dest: Location,
+    /// Number of records written so far.
+    written: Mutex<usize>,
+}
+
+impl Output {
+    /// `src` and `dest` are only used to turn keys into URLs for planned moves in text output.
+    pub fn new(format: Format, src: Location, dest: Location) -> Output {
+        Output { format, src, dest, written: Mutex::new(0) }
+    }
+
+    pub fn write(&self, record: &Record) -> Result<(), BoxError> {
+        let stdout = io::stdout();
+        let stderr = io::stderr();
+        let (mut out, mut err) = (stdout.lock(), stderr.lock());
+        self.write_to(&mut out, &mut err, record)
+    }
+
+    /// Closes the JSON array, or writes the CSV header if no records were written.
+    pub fn finish(&self) -> Result<(), BoxError> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        self.finish_to(&mut out)
+    }
+
+    /// Writes a record to `out`, or in text mode to `err` if it failed or was skipped.
+    fn write_to<W: Write, E: Write>(&self, out: &mut W, err: &mut E, record: &Record) -> Result<(), BoxError> {
+        let mut written = self.written.lock().unwrap();
+        match self.format {
+            Format::Text => self.write_text(out, err, record)?,
+            Format::Json => {
+                out.write_all(if *written == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *out, record)?;
+            }
+            Format::Jsonl => {
+                serde_json::to_writer(&mut *out, record)?;
+                out.write_all(b"\n")?;
+            }
+            Format::Csv => {
+                if *written == 0 {
+                    writeln!(out, "{}", CSV_HEADER)?;
+                }
+                writeln!(out, "{},{},{},{},{},{},{},{}",
+                         csv_field(&record.src_key),
+                         csv_field(record.version_id.as_ref().map_or("", String::as_str)),
+                         csv_field(&record.dest_key),
+                         record.size.map_or(String::new(), |size| size.to_string()),
+                         csv_field(record.etag.as_ref().map_or("", String::as_str)),
+                         csv_value(&record.action)?,
+                         csv_value(&record.status)?,
+                         csv_field(record.error.as_ref().map_or("", String::as_str)))?;
+            }
+        }
+        *written += 1;
+        Ok(())
+    }
+
+    fn finish_to<W: Write>(&self, out: &mut W) -> Result<(), BoxError> {
+        let written = self.written.lock().unwrap();
+        match self.format {
+            Format::Json if *written == 0 => out.write_all(b"[]\n")?,
+            Format::Json => out.write_all(b"\n]\n")?,
+            Format::Csv if *written == 0 => writeln!(out, "{}", CSV_HEADER)?,
+            _ => {}
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn write_text<W: Write, E: Write>(&self, out: &mut W, err: &mut E, record: &Record) -> Result<(), BoxError> {
+        let src = versioned(&record.src_key, &record.version_id);
+        let error = record.error.as_ref().map_or("", String::as_str);
+        let size = record.size.map_or(String::new(), |size| size.to_string());
+        match record.status {
+            Status::Planned => {
+                let src_url = match record.action {
+                    Action::Delete => String::new(),
+                    Action::Move | Action::Copy => versioned(&self.src.object_url(&record.src_key), &record.version_id),
+                };
+                writeln!(out, "{}\t{}\t{}", src_url, self.dest.object_url(&record.dest_key), size)?
+            }
+            Status::Done => writeln!(out, "{}\t{}\t{}", src, record.dest_key, size)?,
+            Status::Unchanged => {}
+            Status::Skipped => writeln!(err, "skipped {}: {}", src, error)?,
+            Status::Failed => writeln!(err, "failed to move {}: {}", src, error)?,
+        }
+        Ok(())
+    }
+}
+
+/// Names a src object, along with its version if a specific one is being moved.
+fn versioned(name: &str, version_id: &Option<String>) -> String {
+    match *version_id {
+        Some(ref version_id) => format!("{}?versionId={}", name, version_id),
+        None => name.to_owned(),
+    }
+}
+
+/// Quotes a CSV field if it contains a delimiter, quote or line break.
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Renders an enum value the same way it appears in JSON.
+fn csv_value<T: ::serde::Serialize>(value: &T) -> Result<String, BoxError> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(s) => Ok(s),
+        other => Ok(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn output(format: Format) -> Output {
+        Output::new(format, Location::Local(PathBuf::from("/src")), Location::Local(PathBuf::from("/dest")))
+    }
+
+    fn record(key: &str, status: Status, error: Option<&str>) -> Record {
+        Record {
+            src_key: key.to_owned(),
+            version_id: None,
+            dest_key: key.to_owned(),
+            size: Some(5),
+            etag: Some("\"abc\"".to_owned()),
+            action: Action::Move,
+            status,
+            error: error.map(str::to_owned),
+        }
+    }
+
+    /// Writes the records and finishes, returning what went to stdout and stderr.
+    fn render(format: Format, records: &[Record]) -> (String, String) {
+        let output = output(format);
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        for record in records {
+            output.write_to(&mut out, &mut err, record).unwrap();
+        }
+        output.finish_to(&mut out).unwrap();
+        (String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("a/b.txt"), "a/b.txt");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("\"abc\""), "\"\"\"abc\"\"\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+        assert_eq!(csv_field("a\rb"), "\"a\rb\"");
+    }
+
+    #[test]
+    fn writes_csv_header_once() {
+        let (out, _) = render(Format::Csv, &[record("a,b", Status::Done, None), record("c", Status::Done, None)]);
+        assert_eq!(out, format!("{}\n\"a,b\",,\"a,b\",5,\"\"\"abc\"\"\",move,done,\n\
+                                 c,,c,5,\"\"\"abc\"\"\",move,done,\n", CSV_HEADER));
+        assert_eq!(render(Format::Csv, &[]).0, format!("{}\n", CSV_HEADER));
+    }
+
+    #[test]
+    fn frames_json_array() {
+        let (out, err) = render(Format::Json, &[record("a", Status::Done, None),
+                                                record("b", Status::Failed, Some("boom"))]);
+        assert!(out.starts_with("[\n{") && out.ends_with("}\n]\n"), "{}", out);
+        assert!(err.is_empty());
+        let records: serde_json::Value = serde_json::from_str(&out).unwrap();
+        let records = records.as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["src_key"], "a");
+        assert_eq!(records[1]["status"], "failed");
+        assert_eq!(records[1]["error"], "boom");
+    }
+
+    #[test]
+    fn writes_empty_json_array() {
+        let (out, _) = render(Format::Json, &[]);
+        assert_eq!(out, "[]\n");
+        assert_eq!(serde_json::from_str::<Vec<serde_json::Value>>(&out).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn writes_one_json_record_per_line() {
+        let (out, _) = render(Format::Jsonl, &[record("a", Status::Done, None), record("b", Status::Done, None)]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            serde_json::from_str::<serde_json::Value>(line).unwrap();
+        }
+    }
+
+    #[test]
+    fn routes_text_failures_and_skips_to_stderr() {
+        let (out, err) = render(Format::Text, &[record("a", Status::Done, None),
+                                                record("b", Status::Failed, Some("boom")),
+                                                record("c", Status::Skipped, Some("dest exists")),
+                                                record("d", Status::Unchanged, None)]);
+        assert_eq!(out, "a\ta\t5\n");
+        assert_eq!(err, "failed to move b: boom\nskipped c: dest exists\n");
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use BoxError;
+
+/// Runs jobs on a fixed number of threads. Jobs are queued on a bounded channel, so `submit`
+/// blocks instead of buffering without limit when the producer gets ahead of the workers.
+///
+/// The first job to fail is kept and returned by `join`. Jobs still queued after a failure are
+/// discarded rather than run.
+pub struct WorkerPool<T> {
+    sender: SyncSender<T>,
+    workers: Vec<JoinHandle<()>>,
+    error: Arc<Mutex<Option<BoxError>>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(workers: usize, queue_len: usize, handler: F) -> WorkerPool<T>
+        where F: Fn(T) -> Result<(), BoxError> + Send + Sync + 'static {
+        let (sender, receiver) = sync_channel(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let error = Arc::new(Mutex::new(None));
+
+        let workers = (0..workers)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let handler = handler.clone();
+                let error = error.clone();
+                thread::spawn(move || loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    if error.lock().unwrap().is_some() {
+                        continue;
+                    }
+                    if let Err(err) = handler(job) {
+                        let mut error = error.lock().unwrap();
+                        if error.is_none() {
+                            *error = Some(err);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        WorkerPool { sender, workers, error }
+    }
+
+    /// Queues a job, blocking while the queue is full.
+    pub fn submit(&self, job: T) -> Result<(), BoxError> {
+        self.sender.send(job).map_err(|_| "all worker threads have exited".into())
+    }
+
+    /// True once any job has failed. Producers should stop submitting work.
+    pub fn failed(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    /// Waits for queued jobs to finish and returns the first error, if any.
+    pub fn join(self) -> Result<(), BoxError> {
+        let WorkerPool { sender, workers, error } = self;
+        drop(sender);
+        for worker in workers {
+            worker.join().map_err(|_| "worker thread panicked")?;
+        }
+        let mut error = error.lock().unwrap();
+        match error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use rand::{self, Rng};
+use rusoto_core::HttpDispatchError;
+use rusoto_s3::{AbortMultipartUploadError, CompleteMultipartUploadError, CopyObjectError, CreateMultipartUploadError,
+                DeleteObjectsError, GetObjectError, GetObjectTaggingError, HeadObjectError, ListObjectVersionsError,
+                ListObjectsV2Error, PutObjectError, UploadPartCopyError, UploadPartError};
+use rusoto_sqs::SendMessageBatchError;
+use std::cmp;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use BoxError;
+
+/// Upper bound on the delay between two attempts, however many retries came before.
+const MAX_DELAY_MS: u64 = 20_000;
+
+/// Error codes of transient failures: throttling, server errors and timeouts. Requests that fail
+/// with any other code, such as AccessDenied or NoSuchBucket, can never succeed on retry.
+const RETRYABLE_CODES: &'static [&'static str] = &[
+    "InternalError",
+    "InternalFailure",
+    "RequestThrottled",
+    // S3 gives this a 400 status, but it only means the connection sat idle for too long.
+    "RequestTimeout",
+    "ServiceUnavailable",
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+];
+
+/// Errors that can tell whether the request that failed might succeed if it's sent again.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+/// Retries failed requests with exponential backoff and full jitter.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Calls `f` until it succeeds, fails with an error that can't succeed on retry, or has been
+    /// retried `max_retries` times.
+    pub fn run<T, E, F>(&self, mut f: F) -> Result<T, E>
+        where E: Retryable, F: FnMut() -> Result<T, E> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Ok(v) => return Ok(v),
+                Err(err) => {
+                    if attempt >= self.max_retries || !err.is_retryable() {
+                        return Err(err);
+                    }
+                    thread::sleep(self.delay(attempt));
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Picks a delay uniformly between zero and `base_delay * 2^attempt`, capped at `MAX_DELAY_MS`.
+    fn delay(&self, attempt: u32) -> Duration {
+        let base_ms = self.base_delay.as_secs() * 1000 + (self.base_delay.subsec_nanos() / 1_000_000) as u64;
+        let ceiling = cmp::min(base_ms.saturating_mul(1 << cmp::min(attempt, 32)), MAX_DELAY_MS);
+        Duration::from_millis(rand::thread_rng().gen_range(0, ceiling + 1))
+    }
+}
+
+/// Implements `Retryable` for rusoto errors. Dropped connections and timeouts show up as
+/// `HttpDispatch`, and are retried. Errors the service models get a variant of their own, like
+/// `NoSuchKey`, and are never retried; neither are credential or validation errors. Anything else
+/// is `Unknown`, holding the raw error body.
+macro_rules! retryable {
+    ($($error:ident),*) => {
+        $(impl Retryable for $error {
+            fn is_retryable(&self) -> bool {
+                match *self {
+                    $error::HttpDispatch(_) => true,
+                    $error::Unknown(ref body) => is_retryable_body(body),
+                    _ => false,
+                }
+            }
+        })*
+    }
+}
+
+retryable!(AbortMultipartUploadError, CompleteMultipartUploadError, CopyObjectError, CreateMultipartUploadError,
+           DeleteObjectsError, GetObjectError, GetObjectTaggingError, HeadObjectError, ListObjectVersionsError,
+           ListObjectsV2Error, PutObjectError, UploadPartCopyError, UploadPartError, SendMessageBatchError);
+
+/// Errors from a request whose response body is read along with it, like GetObject. The request
+/// error is judged as usual, and errors reading the body are dropped connections, which the body
+/// stream gives as `io::Error`.
+impl Retryable for BoxError {
+    fn is_retryable(&self) -> bool {
+        if let Some(err) = self.downcast_ref::<GetObjectError>() {
+            return err.is_retryable();
+        }
+        self.downcast_ref::<io::Error>().is_some() || self.downcast_ref::<HttpDispatchError>().is_some()
+    }
+}
+
+/// Judges an error body by the error code in it, which S3 and SQS give in place of a status
+/// in the body of every 4xx and 5xx response. rusoto keeps only the body, so a HEAD request,
+/// whose error responses have none, can't be told apart from a 403 or 404 and isn't retried.
+/// That's why objects are looked up with ListObjectsV2 or GetObject instead. A body without a
+/// code comes from something other than the service, like a proxy, and is retried.
+fn is_retryable_body(body: &str) -> bool {
+    if body.is_empty() {
+        return false;
+    }
+    match error_code(body) {
+        Some(code) => RETRYABLE_CODES.contains(&code),
+        None => true,
+    }
+}
+
+fn error_code(body: &str) -> Option<&str> {
+    let start = body.find("<Code>")? + "<Code>".len();
+    let len = body[start..].find("</Code>")?;
+    Some(&body[start..start + len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn unknown(code: &str) -> CopyObjectError {
+        CopyObjectError::Unknown(format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code>\
+                                          <Message>...</Message><RequestId>1</RequestId></Error>", code))
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        assert!(unknown("SlowDown").is_retryable());
+        assert!(unknown("InternalError").is_retryable());
+        assert!(unknown("ServiceUnavailable").is_retryable());
+        assert!(CopyObjectError::Unknown("<html>502 Bad Gateway</html>".to_owned()).is_retryable());
+    }
+
+    #[test]
+    fn fails_fast_on_client_errors() {
+        assert!(!unknown("AccessDenied").is_retryable());
+        assert!(!unknown("NoSuchBucket").is_retryable());
+        assert!(!unknown("PreconditionFailed").is_retryable());
+        assert!(!CopyObjectError::ObjectNotInActiveTierError("archived".to_owned()).is_retryable());
+        assert!(!CopyObjectError::Validation("bad key".to_owned()).is_retryable());
+        // A 403 or 404 from HeadObject.
+        assert!(!HeadObjectError::Unknown(String::new()).is_retryable());
+    }
+
+    #[test]
+    fn judges_boxed_errors_by_the_request_error() {
+        let err: BoxError = GetObjectError::Unknown("<Error><Code>SlowDown</Code></Error>".to_owned()).into();
+        assert!(err.is_retryable());
+        let err: BoxError = GetObjectError::NoSuchKey("gone".to_owned()).into();
+        assert!(!err.is_retryable());
+        let err: BoxError = "GetObject returned no body".into();
+        assert!(!err.is_retryable());
+        let err: BoxError = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset").into();
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn stops_at_fatal_error_or_max_retries() {
+        let policy = RetryPolicy { max_retries: 3, base_delay: Duration::from_millis(0) };
+        let calls = Cell::new(0);
+        let result: Result<(), _> = policy.run(|| {
+            calls.set(calls.get() + 1);
+            Err(unknown("SlowDown"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 4);
+
+        calls.set(0);
+        let result: Result<(), _> = policy.run(|| {
+            calls.set(calls.get() + 1);
+            Err(unknown("AccessDenied"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+
+        calls.set(0);
+        let result = policy.run(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 { Err(unknown("InternalError")) } else { Ok(calls.get()) }
+        });
+        assert_eq!(result.ok(), Some(3));
+    }
+}
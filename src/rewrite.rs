@@ -0,0 +1,189 @@
+use regex::Regex;
+
+use BoxError;
+use listing::Listed;
+use template::Template;
+
+/// Selects src keys and maps them to dest keys, using `--src-filter` and either `--dest-replace`
+/// or `--dest-template`. All of them work on the part of the key after the src prefix.
+#[derive(Debug)]
+pub struct KeyRewriter {
+    filter: Option<Regex>,
+    replacement: Option<String>,
+    template: Option<Template>,
+}
+
+impl KeyRewriter {
+    /// Compiles `filter` and checks that every group `replacement` or `template` refers to exists
+    /// in it, so mistakes are reported before anything is moved rather than producing empty keys.
+    pub fn new(filter: Option<&str>, replacement: Option<&str>, template: Option<&str>)
+               -> Result<KeyRewriter, BoxError> {
+        let filter = match filter {
+            Some(filter) => Some(Regex::new(filter)
+                .map_err(|err| format!("invalid --src-filter regex: {}", err))?),
+            None => None,
+        };
+
+        if let Some(replacement) = replacement {
+            let re = filter.as_ref().ok_or("--dest-replace requires --src-filter")?;
+            for name in group_refs(replacement) {
+                let exists = match name.parse::<usize>() {
+                    Ok(i) => i < re.captures_len(),
+                    Err(_) => re.capture_names().any(|n| n == Some(name)),
+                };
+                if exists {
+                    continue;
+                }
+                let mut msg = format!("--dest-replace refers to ${} but --src-filter has no group {}", name, name);
+                let digits = name.find(|c: char| !c.is_ascii_digit()).unwrap_or(name.len());
+                if digits > 0 && digits < name.len() {
+                    msg.push_str(&format!(" (write ${{{}}}{} to follow a group number with text)",
+                                          &name[..digits], &name[digits..]));
+                }
+                return Err(msg.into());
+            }
+        }
+
+        let template = match template {
+            Some(_) if replacement.is_some() => {
+                return Err("--dest-replace and --dest-template can't be used together".into())
+            }
+            Some(template) => Some(Template::parse(template)?),
+            None => None,
+        };
+        for name in template.as_ref().map_or(Vec::new(), Template::groups) {
+            let exists = filter.as_ref().map_or(false, |re| match name.parse::<usize>() {
+                Ok(i) => i < re.captures_len(),
+                Err(_) => re.capture_names().any(|n| n == Some(name)),
+            });
+            if !exists {
+                return Err(format!("--dest-template refers to {{{}}}, which isn't a known value or a group in --src-filter",
+                                   name).into());
+            }
+        }
+
+        Ok(KeyRewriter { filter, replacement: replacement.map(str::to_owned), template })
+    }
+
+    /// True if building dest keys needs more of an object than its key.
+    pub fn needs_object(&self) -> bool {
+        self.template.as_ref().map_or(false, Template::needs_object)
+    }
+
+    /// Returns the dest key for `obj`, whose key part after the src prefix is `key`, or None if
+    /// the filter doesn't match it. Fails if the dest template can't be rendered for `obj`.
+    pub fn rewrite(&self, obj: &Listed, key: &str) -> Result<Option<String>, String> {
+        if let Some(ref template) = self.template {
+            let caps = match self.filter {
+                Some(ref re) => match re.captures(key) {
+                    Some(caps) => Some(caps),
+                    None => return Ok(None),
+                },
+                None => None,
+            };
+            return template.render(obj, key, caps.as_ref()).map(Some);
+        }
+        let re = match self.filter {
+            Some(ref re) => re,
+            None => return Ok(Some(key.to_owned())),
+        };
+        Ok(match self.replacement {
+            Some(ref replacement) => re.captures(key).map(|caps| {
+                let mut result = String::new();
+                caps.expand(replacement, &mut result);
+                result
+            }),
+            None if re.is_match(key) => Some(key.to_owned()),
+            None => None,
+        })
+    }
+}
+
+/// Returns the group names and numbers referred to by `replacement`, following the syntax of
+/// `Captures::expand`: `$name` takes the longest run of `[_0-9A-Za-z]`, `${name}` is delimited
+/// explicitly, and `$$` is a literal `$`.
+fn group_refs(replacement: &str) -> Vec<&str> {
+    let mut refs = Vec::new();
+    let mut rest = replacement;
+    while let Some(i) = rest.find('$') {
+        rest = &rest[i + 1..];
+        if rest.starts_with('$') {
+            rest = &rest[1..];
+        } else if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) if end > 1 => {
+                    refs.push(&rest[1..end]);
+                    rest = &rest[end + 1..];
+                }
+                _ => {}
+            }
+        } else {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if len > 0 {
+                refs.push(&rest[..len]);
+            }
+            rest = &rest[len..];
+        }
+    }
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(key: &str) -> Listed {
+        Listed {
+            key: key.to_owned(),
+            size: 1,
+            etag: None,
+            last_modified: None,
+            version_id: None,
+            storage_class: None,
+        }
+    }
+
+    fn rewrite(rewriter: &KeyRewriter, key: &str) -> Option<String> {
+        rewriter.rewrite(&object(key), key).unwrap()
+    }
+
+    #[test]
+    fn checks_group_references() {
+        assert!(KeyRewriter::new(Some(r"(\d+)/(.*)"), Some("$2/$1"), None).is_ok());
+        assert!(KeyRewriter::new(Some(r"(?P<day>\d+)/(.*)"), Some("${day}x/$$/$2"), None).is_ok());
+        assert!(KeyRewriter::new(Some(r"(\d+)/(.*)"), Some("$3"), None).is_err());
+        assert!(KeyRewriter::new(None, Some("$1"), None).is_err());
+        assert!(KeyRewriter::new(Some("("), None, None).is_err());
+
+        let err = KeyRewriter::new(Some(r"(\d+)/(.*)"), Some("$1x"), None).unwrap_err();
+        assert!(err.to_string().contains("write ${1}x"), "{}", err);
+
+        assert!(KeyRewriter::new(Some(r"(?P<day>\d+)/"), None, Some("{day}/{basename}")).is_ok());
+        assert!(KeyRewriter::new(Some(r"(\d+)/"), None, Some("{2}/{basename}")).is_err());
+        assert!(KeyRewriter::new(None, None, Some("{day}/{basename}")).is_err());
+        assert!(KeyRewriter::new(Some("(.*)"), Some("$1"), Some("{key}")).is_err());
+    }
+
+    #[test]
+    fn rewrites_keys() {
+        let rewriter = KeyRewriter::new(None, None, None).unwrap();
+        assert_eq!(rewrite(&rewriter, "a/b.txt"), Some("a/b.txt".to_owned()));
+
+        let rewriter = KeyRewriter::new(Some(r"\.txt$"), None, None).unwrap();
+        assert_eq!(rewrite(&rewriter, "a/b.txt"), Some("a/b.txt".to_owned()));
+        assert_eq!(rewrite(&rewriter, "a/b.log"), None);
+
+        let rewriter = KeyRewriter::new(Some(r"^(\d{4})-(\d{2})/(.*)$"), Some("$1/$2/$3"), None).unwrap();
+        assert_eq!(rewrite(&rewriter, "2018-06/app.log"), Some("2018/06/app.log".to_owned()));
+        assert_eq!(rewrite(&rewriter, "other/app.log"), None);
+
+        let rewriter = KeyRewriter::new(Some(r"^(?P<app>\w+)-"), None, Some("{app}/{basename}")).unwrap();
+        assert_eq!(rewrite(&rewriter, "logs/web-1.log"), None);
+        assert_eq!(rewrite(&rewriter, "web-1.log"), Some("web/web-1.log".to_owned()));
+        assert!(!rewriter.needs_object());
+
+        let rewriter = KeyRewriter::new(None, None, Some("{etag}/{key}")).unwrap();
+        assert!(rewriter.needs_object());
+        assert!(rewriter.rewrite(&object("a.txt"), "a.txt").is_err());
+    }
+}
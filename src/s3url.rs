@@ -0,0 +1,238 @@
+use regex::{Captures, Regex};
+use serde::de;
+use serde::de::{Deserialize, Deserializer};
+use std::fmt;
+use std::path::PathBuf;
+
+/// A bucket and optional key prefix, parsed from any of the forms S3 locations are written in:
+///
+/// - `s3://bucket` or `s3://bucket/prefix`
+/// - `https://bucket.s3.region.amazonaws.com/prefix`, including the legacy `s3-region` and
+///   region-less `bucket.s3.amazonaws.com` hostnames
+/// - `https://s3.region.amazonaws.com/bucket/prefix`, path-style
+/// - `arn:aws:s3:::bucket/prefix`
+///
+/// HTTPS URLs are percent-decoded. The region is filled in when the hostname names one. Access
+/// point ARNs are rejected, since requests for them have to go to the access point's own
+/// endpoint, which isn't supported yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Url {
+    pub bucket: String,
+    pub prefix: Option<String>,
+    pub region: Option<String>,
+}
+
+impl S3Url {
+    pub fn parse(url: &str) -> Result<S3Url, String> {
+        lazy_static! {
+            static ref S3_SCHEME: Regex = Regex::new("^s3://([^/]+)(?:/(.*))?$").unwrap();
+            static ref VIRTUAL_HOSTED: Regex = Regex::new(
+                r"^https?://([^/]+)\.s3(?:[.-]([a-z0-9-]+))?\.amazonaws\.com(?:\.cn)?(?:/(.*))?$").unwrap();
+            static ref PATH_STYLE: Regex = Regex::new(
+                r"^https?://s3(?:[.-]([a-z0-9-]+))?\.amazonaws\.com(?:\.cn)?/([^/]+)(?:/(.*))?$").unwrap();
+            // Bucket ARNs have no region or account, unlike access point ARNs.
+            static ref BUCKET_ARN: Regex = Regex::new("^arn:aws[a-z-]*:s3:::([^/]+)(?:/(.*))?$").unwrap();
+        }
+
+        fn group(caps: &Captures, i: usize) -> Option<String> {
+            caps.get(i).map(|m| m.as_str().to_owned()).filter(|s| !s.is_empty())
+        }
+        // s3-external-1 is a legacy hostname for us-east-1.
+        fn host_region(caps: &Captures, i: usize) -> Option<String> {
+            group(caps, i).map(|r| if r == "external-1" { "us-east-1".to_owned() } else { r })
+        }
+
+        if let Some(caps) = S3_SCHEME.captures(url) {
+            return Ok(S3Url { bucket: caps[1].to_owned(), prefix: group(&caps, 2), region: None });
+        }
+        if let Some(caps) = BUCKET_ARN.captures(url) {
+            return Ok(S3Url { bucket: caps[1].to_owned(), prefix: group(&caps, 2), region: None });
+        }
+        if url.starts_with("arn:") {
+            return Err(format!("{} is an ARN, but access points aren't supported yet; use s3://bucket/prefix", url));
+        }
+        if let Some(caps) = PATH_STYLE.captures(url) {
+            return Ok(S3Url {
+                bucket: caps[2].to_owned(),
+                prefix: group(&caps, 3).map(|p| percent_decode(&p)),
+                region: host_region(&caps, 1),
+            });
+        }
+        if let Some(caps) = VIRTUAL_HOSTED.captures(url) {
+            return Ok(S3Url {
+                bucket: caps[1].to_owned(),
+                prefix: group(&caps, 3).map(|p| percent_decode(&p)),
+                region: host_region(&caps, 2),
+            });
+        }
+        Err(format!("{} isn't an S3 URL like s3://bucket/some/key", url))
+    }
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = byte {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+struct S3UrlVisitor;
+
+impl<'de> de::Visitor<'de> for S3UrlVisitor {
+    type Value = S3Url;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a URL like s3://bucket/some/key")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: de::Error {
+        S3Url::parse(v).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for S3Url {
+    fn deserialize<D>(d: D) -> Result<S3Url, D::Error> where D: Deserializer<'de> {
+        d.deserialize_str(S3UrlVisitor)
+    }
+}
+
+/// Either end of a move: an S3 location, or a local directory given as a path or `file://` URL.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    S3(S3Url),
+    Local(PathBuf),
+}
+
+impl Location {
+    /// Anything `S3Url::parse` accepts is an S3 location. Other strings are local paths, unless
+    /// they look like a URL or ARN that just failed to parse.
+    pub fn parse(s: &str) -> Result<Location, String> {
+        if s.starts_with("file://") {
+            return Ok(Location::Local(PathBuf::from(percent_decode(&s["file://".len()..]))));
+        }
+        match S3Url::parse(s) {
+            Ok(url) => Ok(Location::S3(url)),
+            Err(err) if s.contains("://") || s.starts_with("s3:") || s.starts_with("arn:") => Err(err),
+            Err(_) if s.is_empty() => Err("expected an S3 URL like s3://bucket/some/key, or a local path".to_owned()),
+            Err(_) => Ok(Location::Local(PathBuf::from(s))),
+        }
+    }
+
+    /// The key prefix objects are listed under or written to. Local keys are relative to the
+    /// directory, so they have none.
+    pub fn prefix(&self) -> Option<&str> {
+        match *self {
+            Location::S3(ref url) => url.prefix.as_ref().map(String::as_str),
+            Location::Local(_) => None,
+        }
+    }
+
+    /// Formats the object at `key` for display.
+    pub fn object_url(&self, key: &str) -> String {
+        match *self {
+            Location::S3(ref url) => format!("s3://{}/{}", url.bucket, key),
+            Location::Local(ref path) => path.join(key).display().to_string(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Location {
+    fn deserialize<D>(d: D) -> Result<Location, D::Error> where D: Deserializer<'de> {
+        let s = String::deserialize(d)?;
+        Location::parse(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(bucket: &str, prefix: Option<&str>, region: Option<&str>) -> S3Url {
+        S3Url {
+            bucket: bucket.to_owned(),
+            prefix: prefix.map(str::to_owned),
+            region: region.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn parses_s3_scheme() {
+        assert_eq!(S3Url::parse("s3://bucket"), Ok(url("bucket", None, None)));
+        assert_eq!(S3Url::parse("s3://bucket/"), Ok(url("bucket", None, None)));
+        assert_eq!(S3Url::parse("s3://bucket/a/b%20c/"), Ok(url("bucket", Some("a/b%20c/"), None)));
+    }
+
+    #[test]
+    fn parses_virtual_hosted() {
+        assert_eq!(S3Url::parse("https://my.bucket.s3.eu-west-1.amazonaws.com/logs/a%2Bb"),
+                   Ok(url("my.bucket", Some("logs/a+b"), Some("eu-west-1"))));
+        assert_eq!(S3Url::parse("https://bucket.s3-us-west-2.amazonaws.com/"),
+                   Ok(url("bucket", None, Some("us-west-2"))));
+        assert_eq!(S3Url::parse("https://bucket.s3.amazonaws.com/key"), Ok(url("bucket", Some("key"), None)));
+        assert_eq!(S3Url::parse("https://bucket.s3-external-1.amazonaws.com"),
+                   Ok(url("bucket", None, Some("us-east-1"))));
+        assert_eq!(S3Url::parse("https://bucket.s3.cn-north-1.amazonaws.com.cn/key"),
+                   Ok(url("bucket", Some("key"), Some("cn-north-1"))));
+        // The bucket ends at the first slash, even if the key looks like another hostname.
+        assert_eq!(S3Url::parse("https://bucket.s3.amazonaws.com/backup/x.s3.amazonaws.com/y"),
+                   Ok(url("bucket", Some("backup/x.s3.amazonaws.com/y"), None)));
+    }
+
+    #[test]
+    fn parses_path_style() {
+        assert_eq!(S3Url::parse("https://s3.ap-south-1.amazonaws.com/bucket/some/key"),
+                   Ok(url("bucket", Some("some/key"), Some("ap-south-1"))));
+        assert_eq!(S3Url::parse("https://s3.amazonaws.com/bucket"), Ok(url("bucket", None, None)));
+    }
+
+    #[test]
+    fn parses_bucket_arns() {
+        assert_eq!(S3Url::parse("arn:aws:s3:::bucket"), Ok(url("bucket", None, None)));
+        assert_eq!(S3Url::parse("arn:aws:s3:::bucket/logs/a b"), Ok(url("bucket", Some("logs/a b"), None)));
+        assert_eq!(S3Url::parse("arn:aws-cn:s3:::bucket/key"), Ok(url("bucket", Some("key"), None)));
+        assert_eq!(Location::parse("arn:aws:s3:::bucket/"), Ok(Location::S3(url("bucket", None, None))));
+        assert!(S3Url::parse("arn:aws:s3:::").is_err());
+    }
+
+    #[test]
+    fn rejects_access_point_arns() {
+        let err = S3Url::parse("arn:aws:s3:us-west-2:123456789012:accesspoint/ap/key").unwrap_err();
+        assert!(err.contains("access points aren't supported"), "{}", err);
+        assert!(Location::parse("arn:aws:s3:us-west-2:123456789012:accesspoint/ap").is_err());
+    }
+
+    #[test]
+    fn rejects_other_urls() {
+        assert!(S3Url::parse("https://example.com/bucket/key").is_err());
+        assert!(S3Url::parse("s3://").is_err());
+        assert!(Location::parse("https://example.com/bucket/key").is_err());
+        assert!(Location::parse("s3:/bucket").is_err());
+        assert!(Location::parse("").is_err());
+    }
+
+    #[test]
+    fn parses_local_paths() {
+        assert_eq!(Location::parse("logs/2018"), Ok(Location::Local(PathBuf::from("logs/2018"))));
+        assert_eq!(Location::parse("file:///var/log/a%20b"), Ok(Location::Local(PathBuf::from("/var/log/a b"))));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%2Fb%20c"), "a/b c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%C3%A9"), "%zzé");
+    }
+}
@@ -0,0 +1,271 @@
+use md5;
+use regex::Captures;
+
+use BoxError;
+use listing::Listed;
+use timestamp;
+
+/// Builds dest keys from a template like `dt={last_modified:%Y-%m-%d}/{basename}`, for
+/// `--dest-template`. Text in braces is replaced by a value of the src object, and may be
+/// followed by helpers that transform it, as in `{key|hash:2}`. `{{` and `}}` are literal braces.
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Value(Value, Vec<Helper>),
+}
+
+#[derive(Debug)]
+enum Value {
+    /// The key part trailing the src prefix.
+    Key,
+    /// Everything in `Key` up to and including the last `/`, if any.
+    Dirname,
+    /// Everything in `Key` after the last `/`.
+    Basename,
+    /// `Basename` without its extension.
+    Stem,
+    /// The extension of `Basename`, without the dot.
+    Ext,
+    Size,
+    /// The src ETag, without quotes.
+    Etag,
+    /// When the src object was last modified, as formatted by `timestamp::format`.
+    LastModified(String),
+    /// A numbered or named group from `--src-filter`.
+    Group(String),
+}
+
+#[derive(Debug)]
+enum Helper {
+    Lower,
+    Upper,
+    /// The first n hex digits of the MD5 of the value, to spread keys evenly across prefixes.
+    Hash(usize),
+}
+
+const DEFAULT_TIME_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, BoxError> {
+        let invalid = |reason: &str| -> BoxError { format!("invalid --dest-template {}: {}", template, reason).into() };
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find(|c| c == '{' || c == '}') {
+            literal.push_str(&rest[..i]);
+            let brace = &rest[i..i + 1];
+            rest = &rest[i + 1..];
+            if rest.starts_with(brace) {
+                literal.push_str(brace);
+                rest = &rest[1..];
+                continue;
+            }
+            if brace == "}" {
+                return Err(invalid("unmatched }, write }} for a literal one"));
+            }
+
+            let end = rest.find('}').ok_or_else(|| invalid("unclosed {"))?;
+            let mut spec = rest[..end].split('|');
+            rest = &rest[end + 1..];
+            let value = parse_value(spec.next().unwrap_or("")).map_err(|reason| invalid(&reason))?;
+            let helpers = spec.map(parse_helper).collect::<Result<_, _>>().map_err(|reason| invalid(&reason))?;
+            if !literal.is_empty() {
+                parts.push(Part::Literal(literal.split_off(0)));
+            }
+            parts.push(Part::Value(value, helpers));
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// The `--src-filter` groups the template refers to.
+    pub fn groups(&self) -> Vec<&str> {
+        self.parts.iter().filter_map(|part| match *part {
+            Part::Value(Value::Group(ref name), _) => Some(name.as_str()),
+            _ => None,
+        }).collect()
+    }
+
+    /// True if the template needs more of an object than its key: its size, ETag or modification
+    /// time.
+    pub fn needs_object(&self) -> bool {
+        self.parts.iter().any(|part| match *part {
+            Part::Value(Value::Size, _) | Part::Value(Value::Etag, _) | Part::Value(Value::LastModified(_), _) => true,
+            _ => false,
+        })
+    }
+
+    /// Builds the dest key for `obj`, whose key part trailing the src prefix is `key`. Fails if
+    /// the template refers to a value that isn't known, like the ETag of a local file or a group
+    /// that didn't match, rather than leaving it empty and building keys like `dt=/...`.
+    pub fn render(&self, obj: &Listed, key: &str, caps: Option<&Captures>) -> Result<String, String> {
+        let basename_start = key.rfind('/').map_or(0, |i| i + 1);
+        let basename = &key[basename_start..];
+        // A leading dot starts a hidden file's name rather than an extension.
+        let (stem, ext) = match basename.rfind('.') {
+            Some(i) if i > 0 => (&basename[..i], &basename[i + 1..]),
+            _ => (basename, ""),
+        };
+
+        let mut rendered = String::with_capacity(key.len() * 2);
+        for part in &self.parts {
+            let (value, helpers) = match *part {
+                Part::Literal(ref s) => {
+                    rendered.push_str(s);
+                    continue;
+                }
+                Part::Value(ref value, ref helpers) => (value, helpers),
+            };
+            let mut s = match *value {
+                Value::Key => key.to_owned(),
+                Value::Dirname => key[..basename_start].to_owned(),
+                Value::Basename => basename.to_owned(),
+                Value::Stem => stem.to_owned(),
+                Value::Ext => ext.to_owned(),
+                Value::Size => obj.size.to_string(),
+                Value::Etag => match obj.etag {
+                    Some(ref etag) => etag.trim_matches('"').to_owned(),
+                    None => return Err("its {etag} isn't known".to_owned()),
+                },
+                Value::LastModified(ref format) => obj.last_modified
+                    .and_then(|secs| timestamp::format(secs, format))
+                    .ok_or("its {last_modified} isn't known")?,
+                Value::Group(ref name) => {
+                    let group = match name.parse::<usize>() {
+                        Ok(i) => caps.and_then(|caps| caps.get(i)),
+                        Err(_) => caps.and_then(|caps| caps.name(name)),
+                    };
+                    group.ok_or_else(|| format!("group {{{}}} of --src-filter didn't match its key", name))?
+                        .as_str()
+                        .to_owned()
+                }
+            };
+            for helper in helpers {
+                s = match *helper {
+                    Helper::Lower => s.to_lowercase(),
+                    Helper::Upper => s.to_uppercase(),
+                    Helper::Hash(n) => format!("{:x}", md5::compute(s.as_bytes()))[..n].to_owned(),
+                };
+            }
+            rendered.push_str(&s);
+        }
+        Ok(rendered)
+    }
+}
+
+fn parse_value(spec: &str) -> Result<Value, String> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    let format = parts.next();
+    let value = match name {
+        "key" => Value::Key,
+        "dirname" => Value::Dirname,
+        "basename" => Value::Basename,
+        "stem" => Value::Stem,
+        "ext" => Value::Ext,
+        "size" => Value::Size,
+        "etag" => Value::Etag,
+        "last_modified" => {
+            let format = format.unwrap_or(DEFAULT_TIME_FORMAT);
+            if timestamp::format(0, format).is_none() {
+                return Err(format!("{} isn't a supported time format; use %Y, %m, %d, %H, %M and %S", format));
+            }
+            return Ok(Value::LastModified(format.to_owned()));
+        }
+        "" => return Err("empty {}".to_owned()),
+        name if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => Value::Group(name.to_owned()),
+        name => return Err(format!("unknown value {{{}}}", name)),
+    };
+    match format {
+        Some(_) => Err(format!("only last_modified takes a format, not {}", name)),
+        None => Ok(value),
+    }
+}
+
+fn parse_helper(helper: &str) -> Result<Helper, String> {
+    let mut parts = helper.splitn(2, ':');
+    match (parts.next().unwrap_or(""), parts.next()) {
+        ("lower", None) => Ok(Helper::Lower),
+        ("upper", None) => Ok(Helper::Upper),
+        ("hash", n) => match n.unwrap_or("2").parse::<usize>() {
+            Ok(n) if n >= 1 && n <= 32 => Ok(Helper::Hash(n)),
+            _ => Err("hash takes a number of hex digits from 1 to 32".to_owned()),
+        },
+        _ => Err(format!("unknown helper {}; use lower, upper or hash:<n>", helper)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn object(key: &str) -> Listed {
+        Listed {
+            key: key.to_owned(),
+            size: 42,
+            etag: Some("\"0123abcd\"".to_owned()),
+            // 2018-06-01T12:30:00Z
+            last_modified: Some(1_527_856_200),
+            version_id: None,
+            storage_class: None,
+        }
+    }
+
+    fn render(template: &str, obj: &Listed, key: &str) -> Result<String, String> {
+        Template::parse(template).unwrap().render(obj, key, None)
+    }
+
+    #[test]
+    fn renders_object_values() {
+        let obj = object("logs/2018/app.log.gz");
+        let key = "2018/app.log.gz";
+        assert_eq!(render("dt={last_modified:%Y-%m-%d}/{basename}", &obj, key).unwrap(), "dt=2018-06-01/app.log.gz");
+        assert_eq!(render("{dirname}|{stem}|{ext}|{size}|{etag}", &obj, key).unwrap(), "2018/|app.log|gz|42|0123abcd");
+        assert_eq!(render("{{{key|upper}}}", &obj, key).unwrap(), "{2018/APP.LOG.GZ}");
+        assert_eq!(render("{last_modified}", &obj, key).unwrap(), "2018-06-01T12:30:00Z");
+        assert_eq!(render("{key|hash:4}", &obj, key).unwrap(), &format!("{:x}", md5::compute(key))[..4]);
+        assert_eq!(render("{stem}.{ext}", &obj, ".hidden").unwrap(), ".hidden.");
+    }
+
+    #[test]
+    fn renders_filter_groups() {
+        let re = Regex::new(r"^(?P<year>\d{4})/(\w+)").unwrap();
+        let template = Template::parse("{year}/{2}/{basename}").unwrap();
+        assert_eq!(template.groups(), vec!["year", "2"]);
+        let obj = object("2018/app/x.log");
+        let caps = re.captures("2018/app/x.log");
+        assert_eq!(template.render(&obj, "2018/app/x.log", caps.as_ref()).unwrap(), "2018/app/x.log");
+    }
+
+    #[test]
+    fn fails_on_missing_values() {
+        let mut obj = object("a.txt");
+        obj.etag = None;
+        obj.last_modified = None;
+        assert!(render("{etag}/{key}", &obj, "a.txt").is_err());
+        assert!(render("dt={last_modified:%Y-%m-%d}/{key}", &obj, "a.txt").is_err());
+        assert_eq!(render("{size}/{key}", &obj, "a.txt").unwrap(), "42/a.txt");
+
+        let re = Regex::new(r"^(\d+)?(.*)$").unwrap();
+        let caps = re.captures("a.txt");
+        assert!(Template::parse("{1}/{2}").unwrap().render(&obj, "a.txt", caps.as_ref()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in &["{", "}", "{}", "{key", "{key:%Y}", "{last_modified:%j}", "{key|hash:0}", "{key|trim}",
+                          "{bad-name}"] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+        assert!(Template::parse("{{}}").is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Parses a UTC timestamp like `2018-06-01T12:30:00.000Z`, as S3 lists them, into seconds since
+/// the Unix epoch. Fractions of a second are dropped.
+pub fn parse(s: &str) -> Option<i64> {
+    let s = s.trim_end_matches('Z');
+    let (date, time) = match s.find('T') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return None,
+    };
+    let days = parse_date(date)?;
+    let time = time.split('.').next()?;
+    let hms = time.split(':').map(|n| n.parse::<i64>().ok()).collect::<Option<Vec<_>>>()?;
+    match hms.as_slice() {
+        &[h, m, s] if h < 24 && m < 60 && s < 61 => Some(days * 86_400 + h * 3_600 + m * 60 + s),
+        _ => None,
+    }
+}
+
+/// Converts a local file's modification time to seconds since the Unix epoch.
+pub fn from_system_time(time: SystemTime) -> Option<i64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs() as i64)
+}
+
+/// Formats seconds since the Unix epoch as a UTC time, following `format`. Supports `%Y`, `%m`,
+/// `%d`, `%H`, `%M`, `%S` and `%%`, and returns None for any other directive.
+pub fn format(secs: i64, format: &str) -> Option<String> {
+    let days = secs.div_euclid(86_400);
+    let secs = secs.rem_euclid(86_400);
+    let (y, m, d) = civil_from_days(days);
+    let mut formatted = String::with_capacity(format.len() + 8);
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'Y' => formatted.push_str(&format!("{:04}", y)),
+            'm' => formatted.push_str(&format!("{:02}", m)),
+            'd' => formatted.push_str(&format!("{:02}", d)),
+            'H' => formatted.push_str(&format!("{:02}", secs / 3_600)),
+            'M' => formatted.push_str(&format!("{:02}", secs / 60 % 60)),
+            'S' => formatted.push_str(&format!("{:02}", secs % 60)),
+            '%' => formatted.push('%'),
+            _ => return None,
+        }
+    }
+    Some(formatted)
+}
+
+/// Parses `YYYY-MM-DD` into days since the Unix epoch.
+fn parse_date(date: &str) -> Option<i64> {
+    let ymd = date.split('-').map(|n| n.parse::<i64>().ok()).collect::<Option<Vec<_>>>()?;
+    match ymd.as_slice() {
+        &[y, m, d] if m >= 1 && m <= 12 && d >= 1 && d <= days_in_month(y, m) => Some(days_from_civil(y, m, d)),
+        _ => None,
+    }
+}
+
+/// The number of days in month `m` of year `y`.
+fn days_in_month(y: i64, m: i64) -> i64 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, using Howard Hinnant's
+/// `days_from_civil` algorithm.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The date of a day since 1970-01-01 as year, month and day, the inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
@@ -0,0 +1,1295 @@
+use futures::{Future, Stream};
+use md5;
+use rusoto_s3::{self, S3};
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use BoxError;
+use journal::Journal;
+use listing::Listed;
+use retry::RetryPolicy;
+use timestamp;
+
+/// CopyObject can't copy objects larger than 5 GiB. Anything bigger is copied in parts.
+const MAX_COPY_OBJECT_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+/// Size of each UploadPartCopy range when the parts of the src object aren't known, unless the
+/// object is too big to fit in `MAX_PARTS` parts.
+const COPY_PART_SIZE: i64 = 512 * 1024 * 1024;
+const MAX_PARTS: i64 = 10_000;
+/// Objects copied through this process, including uploads and downloads, are read into memory,
+/// so they're transferred in smaller parts than server-side copies use.
+const STREAM_PART_SIZE: i64 = 16 * 1024 * 1024;
+/// DeleteObjects accepts at most 1000 keys per call.
+const MAX_DELETE_BATCH: usize = 1000;
+
+/// A single object to move.
+#[derive(Debug, Clone)]
+pub struct MoveOp {
+    pub src_key: String,
+    pub dest_key: String,
+    /// None for a key named by a manifest until `move_object` looks it up.
+    pub size: Option<i64>,
+    /// ETag of the src object as listed, including the surrounding quotes.
+    pub etag: Option<String>,
+    /// When the src object was last modified, in seconds since the Unix epoch.
+    pub last_modified: Option<i64>,
+    /// If set, this version of the src object is moved, and deleted for good afterwards.
+    pub version_id: Option<String>,
+}
+
+impl MoveOp {
+    /// The size of the src object, which `move_object` looks up before anything else.
+    fn src_size(&self) -> i64 {
+        self.size.expect("src object wasn't looked up")
+    }
+}
+
+/// Moves finished by a call to `Mover`.
+#[derive(Debug, Default)]
+pub struct Completed {
+    /// Objects that were copied and, unless the src is being kept, deleted.
+    pub moved: Vec<MoveOp>,
+    /// Objects that were copied but couldn't be deleted, with the error S3 gave for each.
+    pub failed: Vec<(MoveOp, String)>,
+    /// Objects left alone because of a conflict at dest, with the reason for each.
+    pub skipped: Vec<(MoveOp, String)>,
+    /// Keys named by a manifest that aren't in src.
+    pub missing: Vec<MoveOp>,
+}
+
+/// What to do when a dest key is already taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnConflict {
+    /// Replace the existing dest object.
+    Overwrite,
+    /// Leave both the src and the existing dest object alone.
+    Skip,
+    /// Treat the object as already copied if the existing dest object has the same size and
+    /// ETag, and otherwise replace it.
+    SkipIfSame,
+    /// Stop with an error.
+    Fail,
+    /// Copy to the first free key made by adding `-1`, `-2` and so on before the extension.
+    Suffix,
+}
+
+/// The outcome of checking dest for a conflicting object.
+enum Resolution {
+    Copy,
+    /// An identical object is already there.
+    AlreadyCopied,
+    Skip(String),
+}
+
+/// Size and ETag of an object that's already at dest. Local files have no ETag.
+struct Existing {
+    size: i64,
+    etag: Option<String>,
+}
+
+/// Builds the `x-amz-copy-source` value for CopyObject, which must be URL-encoded.
+fn copy_source(bucket: &str, op: &MoveOp) -> String {
+    let source = format!("{}/{}", bucket, percent_encode(&op.src_key, true));
+    match op.version_id {
+        Some(ref version_id) => format!("{}?versionId={}", source, percent_encode(version_id, false)),
+        None => source,
+    }
+}
+
+/// Encodes tags as the URL query string that the `x-amz-tagging` header takes, or returns
+/// None if there are none. Tags are sorted by key so the same set always encodes the same way.
+fn encode_tags(tags: &HashMap<String, String>) -> Option<String> {
+    if tags.is_empty() {
+        return None;
+    }
+    let mut pairs: Vec<_> = tags.iter()
+        .map(|(k, v)| format!("{}={}", percent_encode(k, false), percent_encode(v, false)))
+        .collect();
+    pairs.sort();
+    Some(pairs.join("&"))
+}
+
+fn percent_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Multipart uploads get an ETag of the form `"<hex>-<part count>"`.
+fn is_multipart_etag(etag: &str) -> bool {
+    etag.contains('-')
+}
+
+/// Computes the ETag S3 assigns to a completed multipart upload: the MD5 of the concatenated
+/// binary MD5s of the parts, followed by the part count.
+fn multipart_etag(parts: &[rusoto_s3::CompletedPart]) -> Result<String, BoxError> {
+    let mut digests = Vec::with_capacity(parts.len() * 16);
+    for part in parts {
+        let etag = part.e_tag.as_ref().ok_or("no ETag returned for part")?;
+        let digest = decode_hex(etag.trim_matches('"'))
+            .ok_or_else(|| format!("unexpected part ETag {}", etag))?;
+        digests.extend(digest);
+    }
+    Ok(format!("\"{:x}-{}\"", md5::compute(&digests), parts.len()))
+}
+
+/// Works out the ETag of data as it's read in order: the MD5 of all of it, or, given the byte
+/// ranges of the parts it was uploaded in, its multipart ETag.
+struct EtagHasher {
+    /// Inclusive end offset of each part. Empty for a single-part ETag.
+    part_ends: Vec<i64>,
+    pos: i64,
+    md5: md5::Context,
+    /// Binary MD5s of the parts read so far.
+    digests: Vec<u8>,
+}
+
+impl EtagHasher {
+    fn new(parts: Option<&[(i64, i64)]>) -> EtagHasher {
+        EtagHasher {
+            part_ends: parts.map_or(Vec::new(), |parts| parts.iter().map(|&(_, end)| end).collect()),
+            pos: 0,
+            md5: md5::Context::new(),
+            digests: Vec::new(),
+        }
+    }
+
+    fn consume(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let end = match self.part_ends.get(self.digests.len() / 16) {
+                Some(&end) => end,
+                None => break,
+            };
+            let n = cmp::min(data.len() as i64, end - self.pos + 1) as usize;
+            self.md5.consume(&data[..n]);
+            self.pos += n as i64;
+            data = &data[n..];
+            if self.pos > end {
+                let md5 = mem::replace(&mut self.md5, md5::Context::new());
+                self.digests.extend_from_slice(&md5.compute().0);
+            }
+        }
+        self.md5.consume(data);
+    }
+
+    fn finish(self) -> String {
+        if self.part_ends.is_empty() {
+            format!("\"{:x}\"", self.md5.compute())
+        } else {
+            format!("\"{:x}-{}\"", md5::compute(&self.digests), self.part_ends.len())
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// Maps a key to a path under `root`, refusing keys that would escape it.
+fn local_path(root: &Path, key: &str) -> Result<PathBuf, BoxError> {
+    let rel = Path::new(key);
+    let escapes = rel.components().any(|c| match c {
+        Component::Normal(_) | Component::CurDir => false,
+        _ => true,
+    });
+    if escapes {
+        return Err(format!("key {} can't be mapped to a local path", key).into());
+    }
+    Ok(root.join(rel))
+}
+
+/// A hidden path next to `path` to download into. The pid and a counter keep it from clashing
+/// with the destination of any other download, in this process or a concurrent one.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.{}.s3-bulk-move-tmp", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    path.with_file_name(name)
+}
+
+/// The ETag a file gets from a single-part upload: the MD5 of its contents.
+fn file_etag(path: &Path) -> Result<String, BoxError> {
+    let mut file = File::open(path)?;
+    let mut md5 = md5::Context::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        md5.consume(&buf[..n]);
+    }
+    Ok(format!("\"{:x}\"", md5.compute()))
+}
+
+/// Inserts `-n` before the extension of the last path segment of `key`, if it has one.
+fn suffixed(key: &str, n: usize) -> String {
+    let name_start = key.rfind('/').map_or(0, |i| i + 1);
+    match key[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => format!("{}-{}{}", &key[..name_start + dot], n, &key[name_start + dot..]),
+        _ => format!("{}-{}", key, n),
+    }
+}
+
+fn read_range(path: &Path, start: i64, len: i64) -> Result<Vec<u8>, BoxError> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start as u64))?;
+    let mut data = vec![0; len as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn range(start: i64, end: i64) -> String {
+    format!("bytes={}-{}", start, end)
+}
+
+/// What to do with the tags of src objects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaggingDirective {
+    /// Keep the src tags.
+    Copy,
+    /// Replace the src tags with `WriteOptions::tags`.
+    Replace,
+    /// Add `WriteOptions::tags` to the src tags, overwriting any with the same key.
+    Merge,
+}
+
+impl Default for TaggingDirective {
+    fn default() -> TaggingDirective {
+        TaggingDirective::Copy
+    }
+}
+
+/// Settings applied to every object written to dest.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    pub storage_class: Option<String>,
+    /// `AES256` or `aws:kms`.
+    pub sse: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    /// A canned ACL, like `bucket-owner-full-control`.
+    pub acl: Option<String>,
+    /// If true, copies within S3 get `metadata` instead of the src object's user metadata.
+    pub replace_metadata: bool,
+    pub metadata: HashMap<String, String>,
+    /// Only applies to src objects in S3. Uploaded files always get `tags`.
+    pub tagging_directive: TaggingDirective,
+    pub tags: HashMap<String, String>,
+    /// If true, multipart src objects are copied within S3 in the same parts even when they'd fit
+    /// in a CopyObject, so the copy keeps the src ETag.
+    pub preserve_parts: bool,
+}
+
+impl WriteOptions {
+    fn metadata(&self) -> Option<HashMap<String, String>> {
+        if self.metadata.is_empty() {
+            None
+        } else {
+            Some(self.metadata.clone())
+        }
+    }
+}
+
+/// Content headers, user metadata and tags for a dest object. CopyObject carries these over by
+/// itself, but multipart copies and uploads have to set them explicitly.
+#[derive(Debug, Default)]
+struct Attributes {
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    content_encoding: Option<String>,
+    content_language: Option<String>,
+    content_type: Option<String>,
+    metadata: Option<HashMap<String, String>>,
+    /// Tags as a URL query string.
+    tagging: Option<String>,
+    /// True if the src object has an `encrypted_etag`.
+    encrypted_src: bool,
+}
+
+/// A bucket and the client used to reach it.
+pub struct Bucket<S> {
+    pub client: S,
+    pub name: String,
+}
+
+/// Where objects are moved from or to.
+pub enum Store<S> {
+    S3(Bucket<S>),
+    /// A local directory. Keys are paths relative to it, with `/` as the separator.
+    Local(PathBuf),
+}
+
+/// Moves objects between buckets, or between a bucket and a local directory. Generic over `S3`
+/// so it can be pointed at any S3-compatible implementation.
+pub struct Mover<S> {
+    pub src: Store<S>,
+    pub dest: Store<S>,
+    /// If set, each completed step is recorded here, and copies it shows were already done are
+    /// skipped.
+    pub journal: Option<Journal>,
+    pub retry: RetryPolicy,
+    /// If false, src objects are copied and left in place.
+    pub delete_src: bool,
+    /// If true, objects are copied between buckets by downloading and re-uploading them rather
+    /// than server-side.
+    pub stream: bool,
+    pub write: WriteOptions,
+    /// Src objects in S3 that have been copied and verified, waiting to be deleted in a batch.
+    pub pending_deletes: Mutex<Vec<MoveOp>>,
+    pub on_conflict: OnConflict,
+    /// Dest keys taken by copies in this run, with the src key copied to each, so two copies
+    /// never pick the same free key with `OnConflict::Suffix`, and later versions of a src object
+    /// don't take the copy of an earlier one for a conflict. Keys found taken at dest map to None.
+    pub claimed: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl<S: S3> Mover<S> {
+    /// Copies an object to dest, then deletes the source unless `delete_src` is false. The
+    /// source is only deleted once the copy has succeeded and been verified.
+    ///
+    /// Src objects in S3 are queued and deleted with DeleteObjects once a full batch is
+    /// waiting, so the move may complete in a later call, along with others. Call
+    /// `flush_deletes` once all objects have been copied to delete the rest.
+    ///
+    /// With `OnConflict::Suffix`, the dest key of the returned op is the one actually used. Ops
+    /// without a size are looked up in src first, and come back as missing if they aren't there.
+    pub fn move_object(&self, mut op: MoveOp) -> Result<Completed, BoxError> {
+        if op.size.is_none() {
+            match self.find_src(&op.src_key)? {
+                Some(obj) => {
+                    op.size = Some(obj.size);
+                    op.etag = obj.etag;
+                    op.last_modified = obj.last_modified;
+                }
+                None => return Ok(Completed { missing: vec![op], ..Default::default() }),
+            }
+        }
+        if let Some(reason) = self.copy_and_verify(&mut op)? {
+            return Ok(Completed { skipped: vec![(op, reason)], ..Default::default() });
+        }
+        if !self.delete_src {
+            return Ok(Completed { moved: vec![op], ..Default::default() });
+        }
+
+        let root = match self.src {
+            Store::S3(_) => {
+                let batch = {
+                    let mut pending = self.pending_deletes.lock().unwrap();
+                    pending.push(op);
+                    if pending.len() < MAX_DELETE_BATCH {
+                        return Ok(Completed::default());
+                    }
+                    mem::replace(&mut *pending, Vec::new())
+                };
+                return self.delete_batch(batch);
+            }
+            Store::Local(ref root) => root,
+        };
+
+        fs::remove_file(local_path(root, &op.src_key)?)?;
+        if let Some(ref journal) = self.journal {
+            journal.record_delete(&op.src_key, None)?;
+        }
+        Ok(Completed { moved: vec![op], ..Default::default() })
+    }
+
+    /// Deletes the src objects still waiting for a batch to fill up.
+    pub fn flush_deletes(&self) -> Result<Completed, BoxError> {
+        let pending = mem::replace(&mut *self.pending_deletes.lock().unwrap(), Vec::new());
+        let mut completed = Completed::default();
+        let mut pending = pending.into_iter().peekable();
+        while pending.peek().is_some() {
+            let batch = self.delete_batch(pending.by_ref().take(MAX_DELETE_BATCH).collect())?;
+            completed.moved.extend(batch.moved);
+            completed.failed.extend(batch.failed);
+        }
+        Ok(completed)
+    }
+
+    /// Deletes up to `MAX_DELETE_BATCH` src objects with a single DeleteObjects call. Keys S3
+    /// fails to delete are returned in `failed` rather than failing the whole batch. If the
+    /// request itself fails, every op in the batch is returned in `failed` with its error.
+    fn delete_batch(&self, batch: Vec<MoveOp>) -> Result<Completed, BoxError> {
+        let src = match self.src {
+            Store::S3(ref src) => src,
+            Store::Local(_) => return Err("can't batch deletes of local files".into()),
+        };
+        let objects = batch.iter()
+            .map(|op| rusoto_s3::ObjectIdentifier { key: op.src_key.clone(), version_id: op.version_id.clone() })
+            .collect();
+        let mut errors = match self.delete_objects(src, objects) {
+            Ok(errors) => errors,
+            Err(err) => {
+                let message = err.to_string();
+                let failed = batch.into_iter().map(|op| (op, message.clone())).collect();
+                return Ok(Completed { failed, ..Default::default() });
+            }
+        };
+
+        let mut completed = Completed::default();
+        for op in batch {
+            match errors.remove(&(op.src_key.clone(), op.version_id.clone())) {
+                Some(message) => completed.failed.push((op, message)),
+                None => {
+                    if let Some(ref journal) = self.journal {
+                        journal.record_delete(&op.src_key, op.version_id.as_ref().map(String::as_str))?;
+                    }
+                    completed.moved.push(op);
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    /// Deletes objects from dest, for when they have no counterpart in src. Returns each key with
+    /// the error S3 gave if it couldn't be deleted.
+    pub fn delete_from_dest(&self, keys: Vec<String>) -> Result<Vec<(String, Option<String>)>, BoxError> {
+        let dest = match self.dest {
+            Store::S3(ref dest) => dest,
+            Store::Local(ref root) => {
+                let mut deleted = Vec::with_capacity(keys.len());
+                for key in keys {
+                    fs::remove_file(local_path(root, &key)?)?;
+                    deleted.push((key, None));
+                }
+                return Ok(deleted);
+            }
+        };
+
+        let mut deleted = Vec::with_capacity(keys.len());
+        for batch in keys.chunks(MAX_DELETE_BATCH) {
+            let objects = batch.iter()
+                .map(|key| rusoto_s3::ObjectIdentifier { key: key.clone(), version_id: None })
+                .collect();
+            let mut errors = self.delete_objects(dest, objects)?;
+            deleted.extend(batch.iter().map(|key| (key.clone(), errors.remove(&(key.clone(), None)))));
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes objects with a single DeleteObjects call and returns the error for each one that
+    /// couldn't be deleted, by key and version ID.
+    fn delete_objects(&self, bucket: &Bucket<S>, objects: Vec<rusoto_s3::ObjectIdentifier>)
+                      -> Result<HashMap<(String, Option<String>), String>, BoxError> {
+        let delete_req = rusoto_s3::DeleteObjectsRequest {
+            bucket: bucket.name.clone(),
+            delete: rusoto_s3::Delete {
+                objects,
+                // Only report the keys that couldn't be deleted.
+                quiet: Some(true),
+            },
+            ..Default::default()
+        };
+        let rsp = self.retry.run(|| bucket.client.delete_objects(&delete_req).sync())?;
+
+        let mut errors = HashMap::new();
+        for err in rsp.errors.unwrap_or_default() {
+            if let Some(key) = err.key {
+                let message = format!("{}: {}", err.code.unwrap_or_default(), err.message.unwrap_or_default());
+                errors.insert((key, err.version_id), message);
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Copies an object to dest, unless the journal shows it was already copied, and verifies
+    /// the copy. Returns the reason if the object was skipped because of a conflict at dest.
+    fn copy_and_verify(&self, op: &mut MoveOp) -> Result<Option<String>, BoxError> {
+        let version_id = op.version_id.clone();
+        let version_id = version_id.as_ref().map(String::as_str);
+        if self.on_conflict == OnConflict::Suffix {
+            // A resumed run has to pick up the key an earlier run settled on.
+            if let Some(dest_key) = self.journal.as_ref().and_then(|j| j.copied_to(&op.src_key, version_id)) {
+                op.dest_key = dest_key.to_owned();
+            }
+        }
+
+        let expected_etag = if self.journal.as_ref().map_or(false, |j| j.is_copied(&op.src_key, version_id, &op.dest_key)) {
+            match op.etag {
+                Some(ref etag) if !is_multipart_etag(etag) => Some(etag.clone()),
+                _ => None,
+            }
+        } else {
+            let etag = match self.resolve_conflict(op)? {
+                Resolution::Copy => self.copy_object(op)?,
+                Resolution::AlreadyCopied => None,
+                Resolution::Skip(reason) => return Ok(Some(reason)),
+            };
+            if let Some(ref journal) = self.journal {
+                journal.record_copy(&op.src_key, version_id, &op.dest_key)?;
+            }
+            etag
+        };
+        self.verify(op, expected_etag.as_ref().map(String::as_str))?;
+        Ok(None)
+    }
+
+    /// Decides what to do about an object already at the dest key, according to `on_conflict`.
+    fn resolve_conflict(&self, op: &mut MoveOp) -> Result<Resolution, BoxError> {
+        match self.on_conflict {
+            OnConflict::Overwrite => return Ok(Resolution::Copy),
+            OnConflict::Suffix => {
+                self.claim_free_key(op)?;
+                return Ok(Resolution::Copy);
+            }
+            OnConflict::Skip | OnConflict::SkipIfSame | OnConflict::Fail => {}
+        }
+        if self.claimed_by_src(op) {
+            return Ok(Resolution::Copy);
+        }
+
+        let existing = match self.existing(&op.dest_key)? {
+            Some(existing) => existing,
+            None => {
+                self.claim(op);
+                return Ok(Resolution::Copy);
+            }
+        };
+        match self.on_conflict {
+            OnConflict::SkipIfSame if self.is_same(op, &existing)? => Ok(Resolution::AlreadyCopied),
+            OnConflict::SkipIfSame => {
+                self.claim(op);
+                Ok(Resolution::Copy)
+            }
+            OnConflict::Fail => Err(format!("not moving {}: {} already exists at dest", op.src_key, op.dest_key).into()),
+            _ => Ok(Resolution::Skip(format!("{} already exists at dest", op.dest_key))),
+        }
+    }
+
+    /// Changes the dest key of `op` to the first of it and its suffixed forms that's neither
+    /// at dest nor claimed by another copy. A key an earlier version of the same src object was
+    /// copied to is reused, so all the versions end up at one key.
+    fn claim_free_key(&self, op: &mut MoveOp) -> Result<(), BoxError> {
+        for n in 0.. {
+            let candidate = if n == 0 { op.dest_key.clone() } else { suffixed(&op.dest_key, n) };
+            {
+                let mut claimed = self.claimed.lock().unwrap();
+                match claimed.get(&candidate).cloned() {
+                    Some(Some(ref src_key)) if *src_key == op.src_key => {
+                        op.dest_key = candidate;
+                        return Ok(());
+                    }
+                    Some(_) => continue,
+                    None => claimed.insert(candidate.clone(), Some(op.src_key.clone())),
+                };
+            }
+            if self.existing(&candidate)?.is_none() {
+                op.dest_key = candidate;
+                return Ok(());
+            }
+            self.claimed.lock().unwrap().insert(candidate, None);
+        }
+        unreachable!()
+    }
+
+    /// Records that a version of the src object is being copied to the dest key of `op`. Only
+    /// versions are recorded, since no other src object is moved more than once.
+    fn claim(&self, op: &MoveOp) {
+        if op.version_id.is_some() {
+            self.claimed.lock().unwrap().insert(op.dest_key.clone(), Some(op.src_key.clone()));
+        }
+    }
+
+    /// True if an earlier version of the src object was copied to the dest key of `op` in this
+    /// run, so what's there isn't a conflict.
+    fn claimed_by_src(&self, op: &MoveOp) -> bool {
+        match self.claimed.lock().unwrap().get(&op.dest_key) {
+            Some(&Some(ref src_key)) => *src_key == op.src_key,
+            _ => false,
+        }
+    }
+
+    /// Looks up the src object at `key`, as listing the src would find it.
+    pub fn find_src(&self, key: &str) -> Result<Option<Listed>, BoxError> {
+        self.find(&self.src, key)
+    }
+
+    /// Looks up the object at `key` in dest.
+    fn existing(&self, key: &str) -> Result<Option<Existing>, BoxError> {
+        Ok(self.find(&self.dest, key)?.map(|obj| Existing { size: obj.size, etag: obj.etag }))
+    }
+
+    /// Looks up the object at `key` in `store`. S3 is listed rather than sent a HeadObject, since
+    /// a missing key gives HeadObject an empty error that can't be told apart from other failures.
+    fn find(&self, store: &Store<S>, key: &str) -> Result<Option<Listed>, BoxError> {
+        let bucket = match *store {
+            Store::S3(ref bucket) => bucket,
+            Store::Local(ref root) => {
+                return match fs::metadata(local_path(root, key)?) {
+                    Ok(metadata) => Ok(Some(Listed {
+                        key: key.to_owned(),
+                        size: metadata.len() as i64,
+                        etag: None,
+                        last_modified: metadata.modified().ok().and_then(timestamp::from_system_time),
+                        version_id: None,
+                        storage_class: None,
+                    })),
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(err) => Err(err.into()),
+                }
+            }
+        };
+
+        let list_req = rusoto_s3::ListObjectsV2Request {
+            bucket: bucket.name.clone(),
+            prefix: Some(key.to_owned()),
+            max_keys: Some(1),
+            ..Default::default()
+        };
+        let rsp = self.retry.run(|| bucket.client.list_objects_v2(&list_req).sync())?;
+        Ok(rsp.contents
+            .unwrap_or_default()
+            .into_iter()
+            .find(|obj| obj.key.as_ref().map(String::as_str) == Some(key))
+            .map(|obj| Listed {
+                key: key.to_owned(),
+                size: obj.size.unwrap_or(0),
+                etag: obj.e_tag,
+                last_modified: obj.last_modified.as_ref().and_then(|t| timestamp::parse(t)),
+                version_id: None,
+                storage_class: obj.storage_class,
+            }))
+    }
+
+    /// True if `existing` holds the same data as the src object. Local files are only hashed
+    /// when compared with a single-part object, whose ETag is the MD5 of its data.
+    fn is_same(&self, op: &MoveOp, existing: &Existing) -> Result<bool, BoxError> {
+        if existing.size != op.src_size() {
+            return Ok(false);
+        }
+        let (etag, path) = match (&op.etag, &existing.etag, &self.src, &self.dest) {
+            (&Some(ref src), &Some(ref dest), _, _) => return Ok(src == dest),
+            (&Some(ref etag), &None, _, &Store::Local(ref root)) => (etag, local_path(root, &op.dest_key)?),
+            (&None, &Some(ref etag), &Store::Local(ref root), _) => (etag, local_path(root, &op.src_key)?),
+            _ => return Ok(false),
+        };
+        if is_multipart_etag(etag) {
+            return Ok(false);
+        }
+        Ok(file_etag(&path)? == *etag)
+    }
+
+    /// Checks the dest object against the src object before the src is deleted. The sizes must
+    /// match, and so must the ETags when the dest ETag is known in advance. Downloads are
+    /// checked against the src ETag as they're written, so only their size is checked here.
+    fn verify(&self, op: &MoveOp, expected_etag: Option<&str>) -> Result<(), BoxError> {
+        let root = match self.dest {
+            Store::S3(ref dest) => return self.verify_s3(dest, op, expected_etag),
+            Store::Local(ref root) => root,
+        };
+
+        let path = local_path(root, &op.dest_key)?;
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_dir() && op.src_size() == 0 {
+            return Ok(());
+        }
+        check_size(op, Some(metadata.len() as i64))
+    }
+
+    fn verify_s3(&self, dest: &Bucket<S>, op: &MoveOp, expected_etag: Option<&str>) -> Result<(), BoxError> {
+        let get_req = rusoto_s3::GetObjectRequest {
+            bucket: dest.name.clone(),
+            key: op.dest_key.clone(),
+            ..Default::default()
+        };
+        let head = self.head(dest, get_req, op.src_size())?;
+
+        check_size(op, object_size(&head))?;
+        if encrypted_etag(&head) {
+            return Ok(());
+        }
+        if let Some(expected) = expected_etag {
+            // The src is only looked up once the ETags differ, since that's rare.
+            if head.e_tag.as_ref().map(String::as_str) != Some(expected) && !self.encrypted_src(op)? {
+                return Err(format!("not deleting {}: expected ETag {} at {} but found {:?}",
+                                   op.src_key, expected, op.dest_key, head.e_tag).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the object and returns the ETag the dest object should have, if it's in S3.
+    fn copy_object(&self, op: &MoveOp) -> Result<Option<String>, BoxError> {
+        match (&self.src, &self.dest) {
+            (&Store::S3(ref src), &Store::S3(ref dest)) if self.stream => self.stream_object(src, dest, op).map(Some),
+            (&Store::S3(ref src), &Store::S3(ref dest)) => self.server_side_copy(src, dest, op),
+            (&Store::Local(ref root), &Store::S3(ref dest)) => self.upload_file(root, dest, op).map(Some),
+            (&Store::S3(ref src), &Store::Local(ref root)) => {
+                self.download_file(src, root, op)?;
+                Ok(None)
+            }
+            (&Store::Local(_), &Store::Local(_)) => Err("can't move between two local paths".into()),
+        }
+    }
+
+    /// Copies within S3 with CopyObject, or UploadPartCopy for objects too big for CopyObject and,
+    /// with `preserve_parts`, multipart src objects. Copies are made conditional on the src ETag,
+    /// so a src object replaced since it was listed isn't copied. Returns the ETag the dest object
+    /// should have, if that can be known.
+    ///
+    /// A multipart src is copied in the same parts it was uploaded in, which gives the copy the
+    /// same ETag. Otherwise the ETag of the copy can't be predicted, and None is returned: when
+    /// CopyObject copies a multipart src, when the src store can't tell what its parts were, and
+    /// when the src has an `encrypted_etag`.
+    fn server_side_copy(&self, src: &Bucket<S>, dest: &Bucket<S>, op: &MoveOp) -> Result<Option<String>, BoxError> {
+        let multipart = op.etag.as_ref().map_or(false, |etag| is_multipart_etag(etag));
+        if (multipart && self.write.preserve_parts) || op.src_size() > MAX_COPY_OBJECT_SIZE {
+            let attrs = self.attributes(Some(src), op)?;
+            let (parts, expected_etag) = match self.src_parts(src, op)? {
+                Some(parts) if !attrs.encrypted_src => (parts, op.etag.clone()),
+                Some(parts) => (parts, None),
+                None => (split(op.src_size(), COPY_PART_SIZE), None),
+            };
+            self.multipart_upload(dest, op, &attrs, parts, |upload_id, part_number, start, end| {
+                let part_req = rusoto_s3::UploadPartCopyRequest {
+                    bucket: dest.name.clone(),
+                    key: op.dest_key.clone(),
+                    copy_source: copy_source(&src.name, op),
+                    copy_source_if_match: op.etag.clone(),
+                    copy_source_range: Some(range(start, end)),
+                    part_number,
+                    upload_id: upload_id.to_owned(),
+                    ..Default::default()
+                };
+                let rsp = self.retry.run(|| dest.client.upload_part_copy(&part_req).sync())?;
+                Ok(rsp.copy_part_result.and_then(|r| r.e_tag))
+            })?;
+            return Ok(expected_etag);
+        }
+
+        // Replacing either the metadata or the tags means setting everything CopyObject would
+        // otherwise have copied, since REPLACE drops the src content headers too.
+        let replace = self.write.replace_metadata || self.write.tagging_directive != TaggingDirective::Copy;
+        let attrs = if replace { self.attributes(Some(src), op)? } else { Attributes::default() };
+        let encrypted_src = attrs.encrypted_src;
+        let directive = if replace { Some("REPLACE".to_owned()) } else { None };
+        let copy_req = rusoto_s3::CopyObjectRequest {
+            bucket: dest.name.clone(),
+            key: op.dest_key.clone(),
+            copy_source: copy_source(&src.name, op),
+            copy_source_if_match: op.etag.clone(),
+            storage_class: self.write.storage_class.clone(),
+            server_side_encryption: self.write.sse.clone(),
+            ssekms_key_id: self.write.sse_kms_key_id.clone(),
+            acl: self.write.acl.clone(),
+            metadata_directive: directive.clone(),
+            tagging_directive: directive,
+            cache_control: attrs.cache_control,
+            content_disposition: attrs.content_disposition,
+            content_encoding: attrs.content_encoding,
+            content_language: attrs.content_language,
+            content_type: attrs.content_type,
+            metadata: attrs.metadata,
+            tagging: attrs.tagging,
+            ..Default::default()
+        };
+        self.retry.run(|| dest.client.copy_object(&copy_req).sync())?;
+        Ok(if encrypted_src || multipart { None } else { op.etag.clone() })
+    }
+
+    /// Finds the byte ranges of the parts a multipart src object was uploaded in. Uploaders split
+    /// objects into equal parts with a smaller or equal last one, so that's assumed if the first
+    /// and last parts fit it, and otherwise every part is looked up. Returns None if the src store
+    /// doesn't report parts.
+    fn src_parts(&self, src: &Bucket<S>, op: &MoveOp) -> Result<Option<Vec<(i64, i64)>>, BoxError> {
+        let head_part = |part_number| {
+            let get_req = rusoto_s3::GetObjectRequest {
+                bucket: src.name.clone(),
+                key: op.src_key.clone(),
+                version_id: op.version_id.clone(),
+                if_match: op.etag.clone(),
+                part_number: Some(part_number),
+                ..Default::default()
+            };
+            self.head(src, get_req, op.src_size())
+        };
+        let part_size = |part_number| -> Result<i64, BoxError> {
+            head_part(part_number)?.content_length
+                .ok_or_else(|| format!("no size returned for part {} of {}", part_number, op.src_key).into())
+        };
+
+        let first = head_part(1)?;
+        let (first_size, count) = match (first.content_length, first.parts_count) {
+            (Some(size), Some(count)) => (size, count),
+            _ => return Ok(None),
+        };
+        let mut sizes = vec![first_size];
+        if count > 1 {
+            let last_size = part_size(count)?;
+            if last_size <= first_size && first_size * (count - 1) + last_size == op.src_size() {
+                sizes.extend((2..count).map(|_| first_size));
+            } else {
+                for part_number in 2..count {
+                    sizes.push(part_size(part_number)?);
+                }
+            }
+            sizes.push(last_size);
+        }
+
+        let mut parts = Vec::with_capacity(sizes.len());
+        let mut start = 0;
+        for size in sizes {
+            parts.push((start, start + size - 1));
+            start += size;
+        }
+        if start != op.src_size() {
+            return Err(format!("parts of {} add up to {} bytes, not {}", op.src_key, start, op.src_size()).into());
+        }
+        Ok(Some(parts))
+    }
+
+    /// Copies the object by downloading it with the src client and uploading it with the dest
+    /// client, for when neither set of credentials can both read src and write dest. The dest
+    /// is checked against the MD5 of what was downloaded, which must match a single-part src
+    /// ETag unless the src has an `encrypted_etag`.
+    fn stream_object(&self, src: &Bucket<S>, dest: &Bucket<S>, op: &MoveOp) -> Result<String, BoxError> {
+        let attrs = self.attributes(Some(src), op)?;
+        if op.src_size() > STREAM_PART_SIZE {
+            let parts = split(op.src_size(), STREAM_PART_SIZE);
+            return self.multipart_upload(dest, op, &attrs, parts, |upload_id, part_number, start, end| {
+                let data = self.download(src, op, Some(range(start, end)))?;
+                self.upload_part(dest, op, upload_id, part_number, data)
+            });
+        }
+
+        let data = self.download(src, op, None)?;
+        let etag = format!("\"{:x}\"", md5::compute(&data));
+        if let Some(ref src_etag) = op.etag {
+            if !is_multipart_etag(src_etag) && !attrs.encrypted_src && *src_etag != etag {
+                return Err(format!("downloaded {} with MD5 {} but its ETag is {}", op.src_key, etag, src_etag).into());
+            }
+        }
+        self.put_object(dest, op, &attrs, data)?;
+        Ok(etag)
+    }
+
+    /// Uploads a local file. A single-part upload's ETag is the MD5 of the data, so the dest is
+    /// checked against the MD5 of what was read rather than whatever S3 reports. The parts of a
+    /// multipart upload are checked the same way as they're uploaded.
+    fn upload_file(&self, root: &Path, dest: &Bucket<S>, op: &MoveOp) -> Result<String, BoxError> {
+        let path = local_path(root, &op.src_key)?;
+        let attrs = self.attributes(None, op)?;
+        if op.src_size() > STREAM_PART_SIZE {
+            let parts = split(op.src_size(), STREAM_PART_SIZE);
+            return self.multipart_upload(dest, op, &attrs, parts, |upload_id, part_number, start, end| {
+                let data = read_range(&path, start, end - start + 1)?;
+                self.upload_part(dest, op, upload_id, part_number, data)
+            });
+        }
+
+        let data = fs::read(&path)?;
+        if data.len() as i64 != op.src_size() {
+            return Err(format!("{} changed size while being moved", path.display()).into());
+        }
+        let etag = format!("\"{:x}\"", md5::compute(&data));
+        self.put_object(dest, op, &attrs, data)?;
+        Ok(etag)
+    }
+
+    /// Downloads an object to a local file. The data is written to a hidden `temp_path` next to
+    /// the destination, which is only renamed into place once it matches the src ETag, so an
+    /// interrupted or corrupt download never looks complete. A multipart ETag is worked out
+    /// from the parts the src was uploaded in. If those can't be found, or the src has an
+    /// `encrypted_etag`, only the size is checked. Keys ending in `/` are treated as directory
+    /// markers and created as directories.
+    fn download_file(&self, src: &Bucket<S>, root: &Path, op: &MoveOp) -> Result<(), BoxError> {
+        let path = local_path(root, &op.dest_key)?;
+        if op.dest_key.ends_with('/') {
+            fs::create_dir_all(&path)?;
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let parts = match op.etag {
+            Some(ref etag) if is_multipart_etag(etag) => self.src_parts(src, op)?,
+            _ => None,
+        };
+        let expected_etag = op.etag.as_ref().filter(|etag| parts.is_some() || !is_multipart_etag(etag));
+
+        let part_path = temp_path(&path);
+
+        let result = (|| -> Result<(), BoxError> {
+            let mut file = OpenOptions::new().write(true).create_new(true).open(&part_path)?;
+            let mut hasher = EtagHasher::new(parts.as_ref().map(Vec::as_slice));
+            let mut start = 0;
+            while start < op.src_size() {
+                let end = cmp::min(start + STREAM_PART_SIZE, op.src_size()) - 1;
+                let data = self.download(src, op, Some(range(start, end)))?;
+                hasher.consume(&data);
+                file.write_all(&data)?;
+                start = end + 1;
+            }
+            file.sync_all()?;
+
+            if let Some(etag) = expected_etag {
+                let actual = hasher.finish();
+                if actual != *etag && !self.encrypted_src(op)? {
+                    return Err(format!("downloaded {} with ETag {} but its ETag is {}",
+                                       op.src_key, actual, etag).into());
+                }
+            }
+            fs::rename(&part_path, &path)?;
+            Ok(())
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&part_path);
+        }
+        result
+    }
+
+    /// Reads the src object, or a byte range of it. Reading the body is retried along with the
+    /// request, since that's where dropped connections show up.
+    fn download(&self, src: &Bucket<S>, op: &MoveOp, range: Option<String>) -> Result<Vec<u8>, BoxError> {
+        let get_req = rusoto_s3::GetObjectRequest {
+            bucket: src.name.clone(),
+            key: op.src_key.clone(),
+            version_id: op.version_id.clone(),
+            if_match: op.etag.clone(),
+            range,
+            ..Default::default()
+        };
+        self.retry.run(|| -> Result<Vec<u8>, BoxError> {
+            let rsp = src.client.get_object(&get_req).sync()?;
+            let body = rsp.body.ok_or("GetObject returned no body")?;
+            Ok(body.concat2().wait()?)
+        })
+    }
+
+    /// Gets the headers of an object without its data. HeadObject would do, but its error
+    /// responses have no body, so a throttled one can't be told apart from a 403 and retried.
+    /// `get_req` is sent instead, for just the first byte unless it asks for a part or `size` is
+    /// 0, and its body is dropped unread.
+    fn head(&self, bucket: &Bucket<S>, mut get_req: rusoto_s3::GetObjectRequest, size: i64)
+            -> Result<rusoto_s3::GetObjectOutput, BoxError> {
+        // A range can't be satisfied for an empty object.
+        if get_req.part_number.is_none() && size > 0 {
+            get_req.range = Some(range(0, 0));
+        }
+        let mut rsp = self.retry.run(|| bucket.client.get_object(&get_req).sync())?;
+        rsp.body = None;
+        Ok(rsp)
+    }
+
+    /// Looks up the attributes the dest object should get: those of the src object, with its
+    /// metadata and tags replaced or merged as `write` says. Local files have no attributes of
+    /// their own, so with no `src` the dest just gets the metadata and tags from `write`.
+    fn attributes(&self, src: Option<&Bucket<S>>, op: &MoveOp) -> Result<Attributes, BoxError> {
+        let src = match src {
+            Some(src) => src,
+            None => {
+                return Ok(Attributes {
+                    metadata: self.write.metadata(),
+                    tagging: encode_tags(&self.write.tags),
+                    ..Default::default()
+                })
+            }
+        };
+
+        let head = self.src_head(src, op)?;
+
+        let mut tags = HashMap::new();
+        if self.write.tagging_directive != TaggingDirective::Replace {
+            let tagging_req = rusoto_s3::GetObjectTaggingRequest {
+                bucket: src.name.clone(),
+                key: op.src_key.clone(),
+                version_id: op.version_id.clone(),
+                ..Default::default()
+            };
+            let rsp = self.retry.run(|| src.client.get_object_tagging(&tagging_req).sync())?;
+            tags.extend(rsp.tag_set.into_iter().map(|tag| (tag.key, tag.value)));
+        }
+        if self.write.tagging_directive != TaggingDirective::Copy {
+            tags.extend(self.write.tags.clone());
+        }
+
+        Ok(Attributes {
+            cache_control: head.cache_control,
+            content_disposition: head.content_disposition,
+            content_encoding: head.content_encoding,
+            content_language: head.content_language,
+            content_type: head.content_type,
+            metadata: if self.write.replace_metadata { self.write.metadata() } else { head.metadata },
+            tagging: encode_tags(&tags),
+            encrypted_src: encrypted_etag(&head),
+        })
+    }
+
+    /// Gets the headers of the src object.
+    fn src_head(&self, src: &Bucket<S>, op: &MoveOp) -> Result<rusoto_s3::GetObjectOutput, BoxError> {
+        let get_req = rusoto_s3::GetObjectRequest {
+            bucket: src.name.clone(),
+            key: op.src_key.clone(),
+            version_id: op.version_id.clone(),
+            if_match: op.etag.clone(),
+            ..Default::default()
+        };
+        self.head(src, get_req, op.src_size())
+    }
+
+    /// True if the src object is in S3 and has an `encrypted_etag`.
+    fn encrypted_src(&self, op: &MoveOp) -> Result<bool, BoxError> {
+        match self.src {
+            Store::S3(ref src) => Ok(encrypted_etag(&self.src_head(src, op)?)),
+            Store::Local(_) => Ok(false),
+        }
+    }
+
+    /// Writes `data` as the whole dest object and returns the ETag S3 reports.
+    fn put_object(&self, dest: &Bucket<S>, op: &MoveOp, attrs: &Attributes, data: Vec<u8>)
+                  -> Result<Option<String>, BoxError> {
+        let put_req = rusoto_s3::PutObjectRequest {
+            bucket: dest.name.clone(),
+            key: op.dest_key.clone(),
+            content_length: Some(data.len() as i64),
+            body: Some(data),
+            storage_class: self.write.storage_class.clone(),
+            server_side_encryption: self.write.sse.clone(),
+            ssekms_key_id: self.write.sse_kms_key_id.clone(),
+            acl: self.write.acl.clone(),
+            cache_control: attrs.cache_control.clone(),
+            content_disposition: attrs.content_disposition.clone(),
+            content_encoding: attrs.content_encoding.clone(),
+            content_language: attrs.content_language.clone(),
+            content_type: attrs.content_type.clone(),
+            metadata: attrs.metadata.clone(),
+            tagging: attrs.tagging.clone(),
+            ..Default::default()
+        };
+        let rsp = self.retry.run(|| dest.client.put_object(&put_req).sync())?;
+        Ok(rsp.e_tag)
+    }
+
+    /// Uploads a part and returns its ETag, which must be the MD5 of `data` unless the part is
+    /// encrypted with SSE-KMS. Since the dest ETag is made from the part ETags, that ties it to
+    /// the data that was read rather than to whatever S3 reports.
+    fn upload_part(&self, dest: &Bucket<S>, op: &MoveOp, upload_id: &str, part_number: i64, data: Vec<u8>)
+                   -> Result<Option<String>, BoxError> {
+        let md5 = format!("\"{:x}\"", md5::compute(&data));
+        let part_req = rusoto_s3::UploadPartRequest {
+            bucket: dest.name.clone(),
+            key: op.dest_key.clone(),
+            content_length: Some(data.len() as i64),
+            body: Some(data),
+            part_number,
+            upload_id: upload_id.to_owned(),
+            ..Default::default()
+        };
+        let rsp = self.retry.run(|| dest.client.upload_part(&part_req).sync())?;
+        let kms = rsp.server_side_encryption.as_ref().map_or(false, |sse| sse == "aws:kms");
+        if !kms && rsp.e_tag.as_ref() != Some(&md5) {
+            return Err(format!("uploaded part {} of {} with MD5 {} but got ETag {:?}",
+                               part_number, op.dest_key, md5, rsp.e_tag).into());
+        }
+        Ok(rsp.e_tag)
+    }
+
+    /// Writes the dest object with `attrs` in `parts`, given as inclusive byte ranges, and returns
+    /// its ETag. `upload_part` is called with the upload ID, part number and byte range of each
+    /// part, and returns the part's ETag. The upload is aborted if any part fails, so a failed
+    /// copy doesn't leave orphaned parts behind. If that fails too, both errors are returned.
+    fn multipart_upload<F>(&self, dest: &Bucket<S>, op: &MoveOp, attrs: &Attributes, parts: Vec<(i64, i64)>,
+                           upload_part: F) -> Result<String, BoxError>
+        where F: Fn(&str, i64, i64, i64) -> Result<Option<String>, BoxError> {
+        let create_req = rusoto_s3::CreateMultipartUploadRequest {
+            bucket: dest.name.clone(),
+            key: op.dest_key.clone(),
+            storage_class: self.write.storage_class.clone(),
+            server_side_encryption: self.write.sse.clone(),
+            ssekms_key_id: self.write.sse_kms_key_id.clone(),
+            acl: self.write.acl.clone(),
+            cache_control: attrs.cache_control.clone(),
+            content_disposition: attrs.content_disposition.clone(),
+            content_encoding: attrs.content_encoding.clone(),
+            content_language: attrs.content_language.clone(),
+            content_type: attrs.content_type.clone(),
+            metadata: attrs.metadata.clone(),
+            tagging: attrs.tagging.clone(),
+            ..Default::default()
+        };
+        let upload_id = self.retry.run(|| dest.client.create_multipart_upload(&create_req).sync())?
+            .upload_id
+            .ok_or("CreateMultipartUpload returned no upload ID")?;
+
+        let result = upload_parts(parts, |part_number, start, end| {
+                upload_part(&upload_id, part_number, start, end)
+            })
+            .and_then(|parts| {
+                let etag = multipart_etag(&parts)?;
+                let complete_req = rusoto_s3::CompleteMultipartUploadRequest {
+                    bucket: dest.name.clone(),
+                    key: op.dest_key.clone(),
+                    upload_id: upload_id.clone(),
+                    multipart_upload: Some(rusoto_s3::CompletedMultipartUpload { parts: Some(parts) }),
+                    ..Default::default()
+                };
+                self.retry.run(|| dest.client.complete_multipart_upload(&complete_req).sync())?;
+                Ok(etag)
+            });
+
+        result.or_else(|err| {
+            let abort_req = rusoto_s3::AbortMultipartUploadRequest {
+                bucket: dest.name.clone(),
+                key: op.dest_key.clone(),
+                upload_id: upload_id.clone(),
+                ..Default::default()
+            };
+            match self.retry.run(|| dest.client.abort_multipart_upload(&abort_req).sync()) {
+                Ok(_) => Err(err),
+                Err(abort_err) => Err(format!("{} (and failed to abort upload {} of {}: {})",
+                                              err, upload_id, op.dest_key, abort_err).into()),
+            }
+        })
+    }
+}
+
+/// Splits `size` bytes into inclusive byte ranges of `part_size`, or of more if it takes that to
+/// fit in `MAX_PARTS` parts.
+fn split(size: i64, part_size: i64) -> Vec<(i64, i64)> {
+    let part_size = cmp::max(part_size, (size + MAX_PARTS - 1) / MAX_PARTS);
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while start < size {
+        let end = cmp::min(start + part_size, size) - 1;
+        parts.push((start, end));
+        start = end + 1;
+    }
+    parts
+}
+
+/// Calls `upload_part` with the part number and byte range of each of `parts`.
+fn upload_parts<F>(parts: Vec<(i64, i64)>, upload_part: F) -> Result<Vec<rusoto_s3::CompletedPart>, BoxError>
+    where F: Fn(i64, i64, i64) -> Result<Option<String>, BoxError> {
+    let mut completed = Vec::with_capacity(parts.len());
+    for (i, (start, end)) in parts.into_iter().enumerate() {
+        let part_number = i as i64 + 1;
+        let e_tag = upload_part(part_number, start, end)?;
+        completed.push(rusoto_s3::CompletedPart { e_tag, part_number: Some(part_number) });
+    }
+    Ok(completed)
+}
+
+/// True if the object a GetObject response is for is encrypted with SSE-KMS or SSE-C. Those give
+/// it an ETag that isn't the MD5 of its data, so it differs between copies of the same data and
+/// can only be checked for a size match.
+fn encrypted_etag(rsp: &rusoto_s3::GetObjectOutput) -> bool {
+    rsp.server_side_encryption.as_ref().map_or(false, |sse| sse == "aws:kms")
+        || rsp.sse_customer_algorithm.is_some()
+}
+
+/// The size of the whole object a GetObject response is for. For a range or part of it, that's
+/// given after the `/` in Content-Range.
+fn object_size(rsp: &rusoto_s3::GetObjectOutput) -> Option<i64> {
+    match rsp.content_range {
+        Some(ref content_range) => content_range.rsplit('/').next().and_then(|size| size.parse().ok()),
+        None => rsp.content_length,
+    }
+}
+
+fn check_size(op: &MoveOp, found: Option<i64>) -> Result<(), BoxError> {
+    if found != op.size {
+        return Err(format!("not deleting {}: copied {} bytes to {} but found {:?}",
+                           op.src_key, op.src_size(), op.dest_key, found).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(etag: &str) -> rusoto_s3::CompletedPart {
+        rusoto_s3::CompletedPart { e_tag: Some(etag.to_owned()), part_number: None }
+    }
+
+    #[test]
+    fn computes_multipart_etags() {
+        let parts = [part("\"0cc175b9c0f1b6a831c399e269772661\""), part("\"92eb5ffee6ae2fec3ad71c777531578f\"")];
+        assert_eq!(multipart_etag(&parts).unwrap(), "\"96e024ba2074fe77e8e965ba43a704be-2\"");
+        assert!(multipart_etag(&[part("\"not hex\"")]).is_err());
+        assert!(multipart_etag(&[rusoto_s3::CompletedPart::default()]).is_err());
+        assert!(is_multipart_etag("\"96e024ba2074fe77e8e965ba43a704be-2\""));
+        assert!(!is_multipart_etag("\"0cc175b9c0f1b6a831c399e269772661\""));
+    }
+
+    #[test]
+    fn hashes_etags_as_data_is_read() {
+        let mut hasher = EtagHasher::new(None);
+        hasher.consume(b"a");
+        hasher.consume(b"b");
+        assert_eq!(hasher.finish(), "\"187ef4436122d1cc2f40dc2b92f0eba0\"");
+
+        let mut hasher = EtagHasher::new(Some(&[(0, 0), (1, 1)]));
+        hasher.consume(b"ab");
+        assert_eq!(hasher.finish(), "\"96e024ba2074fe77e8e965ba43a704be-2\"");
+
+        // Reads don't have to line up with the parts.
+        let mut hasher = EtagHasher::new(Some(&[(0, 2), (3, 4)]));
+        for chunk in &[&b"ab"[..], &b"cd"[..], &b"e"[..]] {
+            hasher.consume(chunk);
+        }
+        assert_eq!(hasher.finish(), "\"fd279fa64fe1fa9a3551a4a88ae83424-2\"");
+    }
+
+    #[test]
+    fn suffixes_before_the_extension() {
+        assert_eq!(suffixed("logs/app.log", 1), "logs/app-1.log");
+        assert_eq!(suffixed("logs/app.log.gz", 2), "logs/app.log-2.gz");
+        assert_eq!(suffixed("logs.d/app", 3), "logs.d/app-3");
+        assert_eq!(suffixed("logs/.hidden", 4), "logs/.hidden-4");
+    }
+
+    #[test]
+    fn splits_into_parts() {
+        assert_eq!(split(0, 5), vec![]);
+        assert_eq!(split(10, 5), vec![(0, 4), (5, 9)]);
+        assert_eq!(split(11, 5), vec![(0, 4), (5, 9), (10, 10)]);
+        // Parts grow to stay within MAX_PARTS.
+        let parts = split(MAX_PARTS * 10 + 1, 5);
+        assert_eq!(parts.len(), 9091);
+        assert_eq!(parts[0], (0, 10));
+        assert_eq!(parts.last().unwrap().1, MAX_PARTS * 10);
+    }
+
+    #[test]
+    fn keeps_local_paths_under_the_root() {
+        let root = Path::new("/data");
+        assert_eq!(local_path(root, "a/b.txt").unwrap(), Path::new("/data/a/b.txt"));
+        assert!(local_path(root, "../etc/passwd").is_err());
+        assert!(local_path(root, "a/../../b").is_err());
+        assert!(local_path(root, "/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn downloads_to_distinct_hidden_paths() {
+        let path = Path::new("/data/a/b.txt");
+        let (first, second) = (temp_path(path), temp_path(path));
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), path.parent());
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".b.txt.") && name.ends_with(".s3-bulk-move-tmp"), "{}", name);
+    }
+}
@@ -0,0 +1,45 @@
+extern crate aws_tools;
+
+mod fake_s3;
+
+use fake_s3::{s3_bulk_move, FakeS3};
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn moves_between_buckets_on_an_endpoint() {
+    let s3 = FakeS3::start();
+    s3.put("src", "logs/a.txt", b"alpha");
+    s3.put("src", "logs/b/c.txt", b"gamma");
+    s3.put("src", "other/d.txt", b"delta");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "s3://src/logs/", "s3://dest/archive/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(s3.keys("dest"), vec!["archive/a.txt", "archive/b/c.txt"]);
+    assert_eq!(s3.get("dest", "archive/b/c.txt"), Some(b"gamma".to_vec()));
+    assert_eq!(s3.keys("src"), vec!["other/d.txt"]);
+
+    let log = s3.log();
+    assert!(log.iter().any(|entry| entry == "PUT /dest/archive/a.txt <- src/logs/a.txt"), "{:?}", log);
+    // Buckets are addressed path-style.
+    assert!(log.iter().all(|entry| entry.contains(" /src") || entry.contains(" /dest")), "{:?}", log);
+}
+
+#[test]
+fn streams_between_endpoints() {
+    let src = FakeS3::start();
+    let dest = FakeS3::start();
+    src.put("src", "a.txt", b"alpha");
+
+    let output = s3_bulk_move(&["--src-endpoint", &src.endpoint, "--dest-endpoint", &dest.endpoint,
+                                "s3://src/", "s3://dest/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(dest.get("dest", "a.txt"), Some(b"alpha".to_vec()));
+    assert!(src.keys("src").is_empty());
+    // Neither store can copy from the other, so the object goes through this process.
+    assert!(src.log().iter().any(|entry| entry.starts_with("GET /src/a.txt")), "{:?}", src.log());
+    assert!(dest.log().iter().all(|entry| !entry.contains(" <- ")), "{:?}", dest.log());
+}
@@ -0,0 +1,516 @@
+//! A minimal in-process stand-in for S3, so the tools can be run against a custom endpoint
+//! without network access. It speaks just enough of the path-style REST API for the requests
+//! s3-bulk-move makes, ignores signatures, and logs every request it gets.
+
+#![allow(dead_code)]
+
+extern crate md5;
+
+use aws_tools::s3url::percent_decode;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Last-Modified of every object, as listings and HeadObject give it.
+const LAST_MODIFIED: &'static str = "2018-06-01T12:00:00.000Z";
+const HTTP_LAST_MODIFIED: &'static str = "Fri, 01 Jun 2018 12:00:00 GMT";
+
+#[derive(Clone)]
+struct Object {
+    data: Vec<u8>,
+    etag: String,
+    /// `aws:kms` for objects stored as if encrypted with SSE-KMS.
+    sse: Option<String>,
+}
+
+/// A request that's made to fail, by method and `bucket/key`.
+struct Failure {
+    method: String,
+    path: String,
+    status: u16,
+    code: String,
+    /// How many more times the request fails.
+    times: usize,
+}
+
+#[derive(Default)]
+struct State {
+    objects: BTreeMap<(String, String), Object>,
+    /// Every version of the objects put with `put_version`, oldest first. The newest is also in
+    /// `objects`.
+    versions: BTreeMap<(String, String), Vec<(String, Object)>>,
+    /// Parts of multipart uploads in progress, by upload ID.
+    uploads: HashMap<String, BTreeMap<i64, Vec<u8>>>,
+    next_upload_id: usize,
+    failures: Vec<Failure>,
+    log: Vec<String>,
+}
+
+pub struct FakeS3 {
+    /// URL to pass as `--src-endpoint` or `--dest-endpoint`.
+    pub endpoint: String,
+    state: Arc<Mutex<State>>,
+}
+
+struct Request {
+    method: String,
+    bucket: String,
+    key: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl FakeS3 {
+    /// Starts serving on a free local port. The server lives until the test process exits.
+    pub fn start() -> FakeS3 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = server_state.clone();
+                thread::spawn(move || serve(stream.unwrap(), &state));
+            }
+        });
+        FakeS3 { endpoint, state }
+    }
+
+    pub fn put(&self, bucket: &str, key: &str, data: &[u8]) {
+        let object = Object { data: data.to_vec(), etag: format!("\"{:x}\"", md5::compute(data)), sse: None };
+        self.state.lock().unwrap().objects.insert((bucket.to_owned(), key.to_owned()), object);
+    }
+
+    /// Stores an object as if it were encrypted with SSE-KMS, which gives it an ETag that isn't
+    /// the MD5 of its data.
+    pub fn put_kms(&self, bucket: &str, key: &str, data: &[u8]) {
+        let object = Object {
+            data: data.to_vec(),
+            etag: format!("\"{:x}\"", md5::compute(format!("kms:{}", key))),
+            sse: Some("aws:kms".to_owned()),
+        };
+        self.state.lock().unwrap().objects.insert((bucket.to_owned(), key.to_owned()), object);
+    }
+
+    /// Stores a new version of an object and returns its version ID.
+    pub fn put_version(&self, bucket: &str, key: &str, data: &[u8]) -> String {
+        let object = Object { data: data.to_vec(), etag: format!("\"{:x}\"", md5::compute(data)), sse: None };
+        let mut state = self.state.lock().unwrap();
+        let id = (bucket.to_owned(), key.to_owned());
+        state.objects.insert(id.clone(), object.clone());
+        let versions = state.versions.entry(id).or_insert_with(Vec::new);
+        let version_id = format!("v{}", versions.len() + 1);
+        versions.push((version_id.clone(), object));
+        version_id
+    }
+
+    /// The version IDs of an object put with `put_version`, oldest first.
+    pub fn versions(&self, bucket: &str, key: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.versions.get(&(bucket.to_owned(), key.to_owned()))
+            .map_or(Vec::new(), |versions| versions.iter().map(|&(ref v, _)| v.clone()).collect())
+    }
+
+    pub fn get(&self, bucket: &str, key: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().objects.get(&(bucket.to_owned(), key.to_owned())).map(|o| o.data.clone())
+    }
+
+    pub fn keys(&self, bucket: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.objects.keys().filter(|&&(ref b, _)| b == bucket).map(|&(_, ref k)| k.clone()).collect()
+    }
+
+    /// Makes every `method` request for `bucket/key` fail with `status` and the error `code`.
+    pub fn fail(&self, method: &str, bucket: &str, key: &str, status: u16, code: &str) {
+        self.fail_times(method, bucket, key, status, code, usize::max_value());
+    }
+
+    /// Makes the next `times` `method` requests for `bucket/key` fail with `status` and the error
+    /// `code`.
+    pub fn fail_times(&self, method: &str, bucket: &str, key: &str, status: u16, code: &str, times: usize) {
+        self.state.lock().unwrap().failures.push(Failure {
+            method: method.to_owned(),
+            path: format!("{}/{}", bucket, key),
+            status,
+            code: code.to_owned(),
+            times,
+        });
+    }
+
+    /// Every request so far, as `METHOD /bucket/key?query`, followed by ` <- source` for copies.
+    pub fn log(&self) -> Vec<String> {
+        self.state.lock().unwrap().log.clone()
+    }
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    while let Some((req, target)) = read_request(&mut reader) {
+        let rsp = {
+            let mut state = state.lock().unwrap();
+            let mut entry = format!("{} {}", req.method, target);
+            if let Some(source) = req.headers.get("x-amz-copy-source") {
+                entry.push_str(&format!(" <- {}", percent_decode(source.trim_start_matches('/'))));
+            }
+            state.log.push(entry);
+            handle(&mut state, &req)
+        };
+
+        // HEAD responses give the length of the body they leave out.
+        let mut head = format!("HTTP/1.1 {} Fake\r\nContent-Length: {}\r\n", rsp.status, rsp.body.len());
+        for &(ref name, ref value) in &rsp.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut out = head.into_bytes();
+        if req.method != "HEAD" {
+            out.extend(rsp.body);
+        }
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads a request and returns it along with its raw target, or None at the end of the stream.
+fn read_request<R: BufRead>(reader: &mut R) -> Option<(Request, String)> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let target = parts.next()?.to_owned();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let colon = line.find(':')?;
+        headers.insert(line[..colon].to_lowercase(), line[colon + 1..].trim().to_owned());
+    }
+
+    let mut body = Vec::new();
+    if headers.get("transfer-encoding").map_or(false, |te| te == "chunked") {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).ok()?;
+            let size = usize::from_str_radix(size.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(len) = headers.get("content-length") {
+        body = vec![0; len.parse().ok()?];
+        reader.read_exact(&mut body).ok()?;
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (&target[..], ""),
+    };
+    let path = percent_decode(path.trim_start_matches('/'));
+    let (bucket, key) = match path.find('/') {
+        Some(i) => (path[..i].to_owned(), path[i + 1..].to_owned()),
+        None => (path.clone(), String::new()),
+    };
+    let query = query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(i) => (percent_decode(&pair[..i]), percent_decode(&pair[i + 1..])),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect();
+    Some((Request { method, bucket, key, query, headers, body }, target))
+}
+
+fn handle(state: &mut State, req: &Request) -> Response {
+    let path = format!("{}/{}", req.bucket, req.key);
+    if let Some(failure) = state.failures.iter_mut().find(|f| f.method == req.method && f.path == path && f.times > 0) {
+        failure.times -= 1;
+        return error(failure.status, &failure.code);
+    }
+
+    if req.key.is_empty() {
+        return match req.method.as_str() {
+            "GET" if req.query.contains_key("list-type") => list(state, req),
+            "GET" if req.query.contains_key("versions") => list_versions(state, req),
+            "POST" if req.query.contains_key("delete") => delete_objects(state, req),
+            _ => error(400, "NotImplemented"),
+        };
+    }
+
+    let id = (req.bucket.clone(), req.key.clone());
+    match req.method.as_str() {
+        "HEAD" | "GET" if req.query.contains_key("tagging") => {
+            xml("<Tagging><TagSet></TagSet></Tagging>".to_owned())
+        }
+        "HEAD" | "GET" => {
+            let object = match find(state, &id, req.query.get("versionId")) {
+                Some(object) => object,
+                None if req.method == "HEAD" => return error(404, ""),
+                None => return error(404, "NoSuchKey"),
+            };
+            if let Some(etag) = req.headers.get("if-match") {
+                if *etag != object.etag {
+                    return error(412, "PreconditionFailed");
+                }
+            }
+            let mut headers = vec![
+                ("ETag".to_owned(), object.etag.clone()),
+                ("Last-Modified".to_owned(), HTTP_LAST_MODIFIED.to_owned()),
+            ];
+            if let Some(ref sse) = object.sse {
+                headers.push(("x-amz-server-side-encryption".to_owned(), sse.clone()));
+            }
+            let (status, data) = match req.headers.get("range").and_then(|r| parse_range(r)) {
+                Some(_) if object.data.is_empty() => return error(416, "InvalidRange"),
+                Some((start, end)) => {
+                    let end = end.min(object.data.len() - 1);
+                    headers.push(("Content-Range".to_owned(),
+                                  format!("bytes {}-{}/{}", start, end, object.data.len())));
+                    (206, object.data[start..end + 1].to_vec())
+                }
+                None => (200, object.data.clone()),
+            };
+            Response { status, headers, body: data }
+        }
+        "PUT" => {
+            let data = match req.headers.get("x-amz-copy-source") {
+                Some(source) => {
+                    let (source, version_id) = match source.find("?versionId=") {
+                        Some(i) => (&source[..i], Some(percent_decode(&source[i + "?versionId=".len()..]))),
+                        None => (source.as_str(), None),
+                    };
+                    let source = percent_decode(source.trim_start_matches('/'));
+                    let slash = source.find('/').unwrap_or(source.len());
+                    let source_id = (source[..slash].to_owned(), source[slash + 1..].to_owned());
+                    let object = match find(state, &source_id, version_id.as_ref()) {
+                        Some(object) => object,
+                        None => return error(404, "NoSuchKey"),
+                    };
+                    if let Some(etag) = req.headers.get("x-amz-copy-source-if-match") {
+                        if *etag != object.etag {
+                            return error(412, "PreconditionFailed");
+                        }
+                    }
+                    match req.headers.get("x-amz-copy-source-range").and_then(|r| parse_range(r)) {
+                        Some((start, end)) => object.data[start..end + 1].to_vec(),
+                        None => object.data.clone(),
+                    }
+                }
+                None => req.body.clone(),
+            };
+            let etag = format!("\"{:x}\"", md5::compute(&data));
+            let copied = req.headers.contains_key("x-amz-copy-source");
+
+            if let Some(upload_id) = req.query.get("uploadId") {
+                let part_number = req.query.get("partNumber").and_then(|n| n.parse().ok()).unwrap_or(0);
+                match state.uploads.get_mut(upload_id) {
+                    Some(parts) => parts.insert(part_number, data),
+                    None => return error(404, "NoSuchUpload"),
+                };
+                if copied {
+                    return xml(format!("<CopyPartResult><LastModified>{}</LastModified><ETag>{}</ETag></CopyPartResult>",
+                                       LAST_MODIFIED, escape(&etag)));
+                }
+                return Response { status: 200, headers: vec![("ETag".to_owned(), etag)], body: Vec::new() };
+            }
+
+            state.objects.insert(id, Object { data, etag: etag.clone(), sse: None });
+            if copied {
+                return xml(format!("<CopyObjectResult><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>",
+                                   LAST_MODIFIED, escape(&etag)));
+            }
+            Response { status: 200, headers: vec![("ETag".to_owned(), etag)], body: Vec::new() }
+        }
+        "POST" if req.query.contains_key("uploads") => {
+            state.next_upload_id += 1;
+            let upload_id = state.next_upload_id.to_string();
+            state.uploads.insert(upload_id.clone(), BTreeMap::new());
+            xml(format!("<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId>\
+                         </InitiateMultipartUploadResult>", escape(&req.bucket), escape(&req.key), upload_id))
+        }
+        "POST" if req.query.contains_key("uploadId") => {
+            let parts = match state.uploads.remove(&req.query["uploadId"]) {
+                Some(parts) => parts,
+                None => return error(404, "NoSuchUpload"),
+            };
+            let mut data = Vec::new();
+            let mut digests = Vec::new();
+            for part in parts.values() {
+                data.extend_from_slice(part);
+                digests.extend_from_slice(&md5::compute(part).0);
+            }
+            let etag = format!("\"{:x}-{}\"", md5::compute(&digests), parts.len());
+            state.objects.insert(id, Object { data, etag: etag.clone(), sse: None });
+            xml(format!("<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag>\
+                         </CompleteMultipartUploadResult>", escape(&req.bucket), escape(&req.key), escape(&etag)))
+        }
+        "DELETE" => {
+            match req.query.get("uploadId") {
+                Some(upload_id) => state.uploads.remove(upload_id).map(|_| ()),
+                None => state.objects.remove(&id).map(|_| ()),
+            };
+            Response { status: 204, headers: Vec::new(), body: Vec::new() }
+        }
+        _ => error(400, "NotImplemented"),
+    }
+}
+
+/// ListObjectsV2, with `prefix`, `start-after`, `max-keys` and continuation tokens, which are
+/// just the last key returned.
+fn list(state: &State, req: &Request) -> Response {
+    let prefix = req.query.get("prefix").cloned().unwrap_or_default();
+    let after = req.query.get("continuation-token").or_else(|| req.query.get("start-after")).cloned();
+    let max_keys = req.query.get("max-keys").and_then(|n| n.parse().ok()).unwrap_or(1000);
+    let matching: Vec<_> = state.objects.iter()
+        .filter(|&(&(ref bucket, ref key), _)| {
+            *bucket == req.bucket && key.starts_with(&prefix) && after.as_ref().map_or(true, |a| key > a)
+        })
+        .collect();
+
+    let mut body = format!("<ListBucketResult><Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys>",
+                           escape(&req.bucket), escape(&prefix), max_keys);
+    for &(&(_, ref key), object) in matching.iter().take(max_keys) {
+        body.push_str(&format!("<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag>\
+                                <Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                               escape(key), LAST_MODIFIED, escape(&object.etag), object.data.len()));
+    }
+    let truncated = matching.len() > max_keys;
+    body.push_str(&format!("<KeyCount>{}</KeyCount><IsTruncated>{}</IsTruncated>",
+                           matching.len().min(max_keys), truncated));
+    if truncated {
+        let &(&(_, ref last), _) = &matching[max_keys - 1];
+        body.push_str(&format!("<NextContinuationToken>{}</NextContinuationToken>", escape(last)));
+    }
+    body.push_str("</ListBucketResult>");
+    xml(body)
+}
+
+/// ListObjectVersions for the objects put with `put_version`, newest version first, with
+/// `prefix` but no paging.
+fn list_versions(state: &State, req: &Request) -> Response {
+    let prefix = req.query.get("prefix").cloned().unwrap_or_default();
+    let mut body = format!("<ListVersionsResult><Name>{}</Name><Prefix>{}</Prefix><IsTruncated>false</IsTruncated>",
+                           escape(&req.bucket), escape(&prefix));
+    for (&(ref bucket, ref key), versions) in &state.versions {
+        if *bucket != req.bucket || !key.starts_with(&prefix) {
+            continue;
+        }
+        for (i, &(ref version_id, ref object)) in versions.iter().enumerate().rev() {
+            body.push_str(&format!("<Version><Key>{}</Key><VersionId>{}</VersionId><IsLatest>{}</IsLatest>\
+                                    <LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size>\
+                                    <StorageClass>STANDARD</StorageClass></Version>",
+                                   escape(key), version_id, i == versions.len() - 1, LAST_MODIFIED,
+                                   escape(&object.etag), object.data.len()));
+        }
+    }
+    body.push_str("</ListVersionsResult>");
+    xml(body)
+}
+
+/// DeleteObjects in quiet mode, so only keys that fail would be reported, and none do. Deleting
+/// the newest version of an object makes the one before it current.
+fn delete_objects(state: &mut State, req: &Request) -> Response {
+    let body = String::from_utf8_lossy(&req.body);
+    for chunk in body.split("<Key>").skip(1) {
+        let end = match chunk.find("</Key>") {
+            Some(end) => end,
+            None => continue,
+        };
+        let id = (req.bucket.clone(), unescape(&chunk[..end]));
+        let version_id = chunk.find("<VersionId>")
+            .and_then(|start| chunk[start..].find("</VersionId>").map(|len| &chunk[start + "<VersionId>".len()..start + len]));
+        let version_id = match version_id {
+            Some(version_id) => unescape(version_id),
+            None => {
+                state.objects.remove(&id);
+                continue;
+            }
+        };
+        let current = match state.versions.get_mut(&id) {
+            Some(versions) => {
+                versions.retain(|&(ref v, _)| *v != version_id);
+                versions.last().map(|&(_, ref object)| object.clone())
+            }
+            None => continue,
+        };
+        match current {
+            Some(object) => state.objects.insert(id, object),
+            None => state.objects.remove(&id),
+        };
+    }
+    xml("<DeleteResult></DeleteResult>".to_owned())
+}
+
+/// The object at `id`, or the given version of it.
+fn find<'a>(state: &'a State, id: &(String, String), version_id: Option<&String>) -> Option<&'a Object> {
+    match version_id {
+        Some(version_id) => state.versions.get(id)?.iter().find(|&&(ref v, _)| v == version_id).map(|&(_, ref o)| o),
+        None => state.objects.get(id),
+    }
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let range = range.trim_start_matches("bytes=");
+    let dash = range.find('-')?;
+    Some((range[..dash].parse().ok()?, range[dash + 1..].parse().ok()?))
+}
+
+fn xml(body: String) -> Response {
+    Response {
+        status: 200,
+        headers: vec![("Content-Type".to_owned(), "application/xml".to_owned())],
+        body: body.into_bytes(),
+    }
+}
+
+/// An S3 error response. HEAD responses have no body, so an empty code sends none.
+fn error(status: u16, code: &str) -> Response {
+    let body = if code.is_empty() {
+        Vec::new()
+    } else {
+        format!("<Error><Code>{}</Code><Message>{}</Message><RequestId>0</RequestId></Error>", code, code).into_bytes()
+    };
+    Response { status, headers: vec![("Content-Type".to_owned(), "application/xml".to_owned())], body }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
+
+/// Runs s3-bulk-move with credentials that the fake accepts, and short retry delays.
+pub fn s3_bulk_move(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_s3-bulk-move"))
+        .arg("--retry-delay=1")
+        .args(args)
+        .env("AWS_ACCESS_KEY_ID", "test")
+        .env("AWS_SECRET_ACCESS_KEY", "test")
+        .env_remove("AWS_SESSION_TOKEN")
+        .env_remove("AWS_PROFILE")
+        .output()
+        .unwrap()
+}
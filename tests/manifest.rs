@@ -0,0 +1,60 @@
+extern crate aws_tools;
+
+mod fake_s3;
+
+use fake_s3::{s3_bulk_move, FakeS3};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("s3-bulk-move-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn reports_manifest_keys_missing_from_src_as_failed() {
+    let s3 = FakeS3::start();
+    s3.put("src", "logs/a.txt", b"alpha");
+    s3.put("src", "logs/b.txt", b"beta");
+    let manifest = temp_path("missing.txt");
+    fs::write(&manifest, "logs/a.txt\nlogs/gone.txt\nlogs/b.txt\n").unwrap();
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "--manifest", manifest.to_str().unwrap(), "--output=jsonl",
+                                "s3://src/logs/", "s3://dest/"]);
+    fs::remove_file(&manifest).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("1 keys in the manifest aren't in src"), "{}", stderr);
+    // The keys after the missing one are still moved.
+    assert_eq!(s3.keys("dest"), vec!["a.txt", "b.txt"]);
+    assert!(s3.keys("src").is_empty());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let failed = stdout.lines().find(|line| line.contains("logs/gone.txt")).expect(&stdout);
+    assert!(failed.contains("\"status\":\"failed\""), "{}", failed);
+    assert!(failed.contains("not in src"), "{}", failed);
+}
+
+#[test]
+fn skips_manifest_keys_the_journal_shows_were_moved() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.txt", b"alpha");
+    s3.put("src", "b.txt", b"beta");
+    let manifest = temp_path("journaled.txt");
+    let journal = temp_path("journaled.journal");
+    fs::write(&manifest, "a.txt\nb.txt\n").unwrap();
+    fs::write(&journal, "{\"op\":\"copied\",\"src_key\":\"a.txt\",\"dest_key\":\"a.txt\"}\n\
+                         {\"op\":\"deleted\",\"src_key\":\"a.txt\"}\n").unwrap();
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "--manifest", manifest.to_str().unwrap(), "--journal", journal.to_str().unwrap(),
+                                "s3://src/", "s3://dest/"]);
+    fs::remove_file(&manifest).unwrap();
+    fs::remove_file(&journal).unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(s3.keys("dest"), vec!["b.txt"]);
+    assert_eq!(s3.keys("src"), vec!["a.txt"]);
+    // a.txt isn't even looked up.
+    assert!(s3.log().iter().all(|entry| !entry.contains("a.txt")), "{:?}", s3.log());
+}
@@ -0,0 +1,198 @@
+extern crate aws_tools;
+
+mod fake_s3;
+
+use fake_s3::{s3_bulk_move, FakeS3};
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn position(log: &[String], prefix: &str) -> usize {
+    log.iter().position(|entry| entry.starts_with(prefix)).unwrap_or_else(|| panic!("no {} in {:?}", prefix, log))
+}
+
+#[test]
+fn deletes_src_only_after_copying_and_verifying() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.txt", b"alpha");
+    s3.put("src", "b.txt", b"beta");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "s3://src/", "s3://dest/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(s3.keys("dest"), vec!["a.txt", "b.txt"]);
+    assert!(s3.keys("src").is_empty());
+
+    let log = s3.log();
+    let delete = position(&log, "POST /src?delete");
+    for key in &["a.txt", "b.txt"] {
+        let copy = position(&log, &format!("PUT /dest/{} <- src/{}", key, key));
+        let verify = position(&log, &format!("GET /dest/{}", key));
+        assert!(copy < verify && verify < delete, "{:?}", log);
+    }
+}
+
+#[test]
+fn keeps_src_when_the_copy_fails() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.txt", b"alpha");
+    s3.put("src", "b.txt", b"beta");
+    s3.fail("PUT", "dest", "b.txt", 403, "AccessDenied");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "--concurrency=1", "s3://src/", "s3://dest/"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("b.txt"), "{}", stderr(&output));
+    assert_eq!(s3.keys("dest"), vec!["a.txt"]);
+    // a.txt was copied before the failure, so it's still deleted.
+    assert_eq!(s3.keys("src"), vec!["b.txt"]);
+    // AccessDenied can't succeed on retry.
+    assert_eq!(s3.log().iter().filter(|entry| entry.starts_with("PUT /dest/b.txt")).count(), 1);
+}
+
+#[test]
+fn keeps_src_when_the_copy_cant_be_verified() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.txt", b"alpha");
+    s3.fail("GET", "dest", "a.txt", 404, "NoSuchKey");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "s3://src/", "s3://dest/"]);
+    assert!(!output.status.success());
+    assert_eq!(s3.keys("src"), vec!["a.txt"]);
+    assert!(s3.log().iter().all(|entry| !entry.starts_with("POST /src?delete")), "{:?}", s3.log());
+}
+
+#[test]
+fn reports_a_failed_delete_batch_per_object() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.txt", b"alpha");
+    s3.put("src", "b.txt", b"beta");
+    s3.fail("POST", "src", "", 403, "AccessDenied");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "--output=jsonl", "s3://src/", "s3://dest/"]);
+    assert!(!output.status.success());
+    assert_eq!(s3.keys("dest"), vec!["a.txt", "b.txt"]);
+    assert_eq!(s3.keys("src"), vec!["a.txt", "b.txt"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for key in &["a.txt", "b.txt"] {
+        let line = stdout.lines().find(|line| line.contains(key)).expect(&stdout);
+        assert!(line.contains("\"status\":\"failed\""), "{}", line);
+        assert!(line.contains("AccessDenied"), "{}", line);
+    }
+}
+
+#[test]
+fn retries_throttled_verification() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.txt", b"alpha");
+    s3.fail_times("GET", "dest", "a.txt", 503, "SlowDown", 2);
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "s3://src/", "s3://dest/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(s3.keys("dest"), vec!["a.txt"]);
+    assert!(s3.keys("src").is_empty());
+    assert_eq!(s3.log().iter().filter(|entry| entry.starts_with("GET /dest/a.txt")).count(), 3);
+}
+
+#[test]
+fn moves_sse_kms_objects_to_unencrypted_dest() {
+    let s3 = FakeS3::start();
+    s3.put_kms("src", "a.txt", b"alpha");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "s3://src/", "s3://dest/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(s3.get("dest", "a.txt"), Some(b"alpha".to_vec()));
+    assert!(s3.keys("src").is_empty());
+}
+
+#[test]
+fn streams_sse_kms_objects() {
+    let src = FakeS3::start();
+    let dest = FakeS3::start();
+    src.put_kms("src", "a.txt", b"alpha");
+
+    let output = s3_bulk_move(&["--src-endpoint", &src.endpoint, "--dest-endpoint", &dest.endpoint,
+                                "s3://src/", "s3://dest/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(dest.get("dest", "a.txt"), Some(b"alpha".to_vec()));
+    assert!(src.keys("src").is_empty());
+}
+
+#[test]
+fn downloads_sse_kms_objects() {
+    let s3 = FakeS3::start();
+    s3.put_kms("src", "a.txt", b"alpha");
+    let dir = std::env::temp_dir().join(format!("s3-bulk-move-{}-kms", std::process::id()));
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "s3://src/", dir.to_str().unwrap()]);
+    let downloaded = std::fs::read(dir.join("a.txt"));
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(downloaded.unwrap(), b"alpha".to_vec());
+    assert!(s3.keys("src").is_empty());
+}
+
+#[test]
+fn rejects_two_local_paths() {
+    let output = s3_bulk_move(&["/tmp/src/", "/tmp/dest/"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("can't both be local paths"), "{}", stderr(&output));
+}
+
+#[test]
+fn moves_all_versions_to_one_key_whatever_the_conflict_policy() {
+    for policy in &["fail", "skip", "skip-if-same", "suffix"] {
+        let s3 = FakeS3::start();
+        s3.put_version("src", "a.txt", b"one");
+        s3.put_version("src", "a.txt", b"two");
+
+        let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                    "--all-versions", &format!("--on-conflict={}", policy), "s3://src/", "s3://dest/"]);
+        assert!(output.status.success(), "{}: {}", policy, stderr(&output));
+        // Each version is copied over the one before it.
+        assert_eq!(s3.keys("dest"), vec!["a.txt"], "{}", policy);
+        assert_eq!(s3.get("dest", "a.txt"), Some(b"two".to_vec()), "{}", policy);
+        assert!(s3.versions("src", "a.txt").is_empty(), "{}", policy);
+        assert!(s3.keys("src").is_empty(), "{}", policy);
+    }
+}
+
+#[test]
+fn leaves_src_alone_with_no_delete() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.txt", b"alpha");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "--no-delete", "s3://src/", "s3://dest/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(s3.get("dest", "a.txt"), Some(b"alpha".to_vec()));
+    assert_eq!(s3.keys("src"), vec!["a.txt"]);
+}
+
+#[test]
+fn keeps_dest_copies_of_filtered_out_keys_when_deleting_extraneous_ones() {
+    let s3 = FakeS3::start();
+    s3.put("src", "a.log", b"alpha");
+    s3.put("src", "b.txt", b"beta");
+    s3.put("dest", "b.txt", b"beta");
+    s3.put("dest", "c.log", b"gamma");
+
+    let output = s3_bulk_move(&["--src-endpoint", &s3.endpoint, "--dest-endpoint", &s3.endpoint,
+                                "--sync", "--delete-extraneous", r"--src-filter=\.log$", "s3://src/", "s3://dest/"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(s3.keys("dest"), vec!["a.log", "b.txt"]);
+    assert_eq!(s3.keys("src"), vec!["a.log", "b.txt"]);
+}
+
+#[test]
+fn rejects_delete_extraneous_when_filtered_out_keys_have_no_dest_key() {
+    let output = s3_bulk_move(&["--sync", "--delete-extraneous", r"--src-filter=(.*)\.log$",
+                                "--dest-replace=logs/$1.log", "s3://src/", "s3://dest/"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("filtered-out src keys"), "{}", stderr(&output));
+}